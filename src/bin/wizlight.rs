@@ -1,12 +1,14 @@
-use wizlight_rs::discovery::BroadcastProtocol;
+use wizlight_rs::cli::{self, Cli};
 use wizlight_rs::Result;
 
+use clap::Parser;
 use tracing::level_filters::LevelFilter;
 use tracing::{info, Level};
 use tracing_appender::rolling;
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
     let log_f = rolling::hourly("./", "runtime.log");
     let (non_block, _guard) = tracing_appender::non_blocking(log_f);
     tracing_subscriber::fmt::Subscriber::builder()
@@ -18,8 +20,5 @@ async fn main() -> Result<()> {
         .with_file(true)
         .try_init()?;
     info!("Initialized subscriber");
-    let mut proto = BroadcastProtocol::new(None)?;
-    proto.discover().await?;
-    info!("{:?}", proto.reg.bulbs());
-    Ok(())
+    cli::run(cli).await
 }
@@ -1,16 +1,887 @@
-use crate::bulblibrary::BulbClass;
+use crate::bulblibrary::{BulbClass, KelvinRange};
 use crate::discovery::BroadcastProtocol;
-use buildstructor::buildstructor;
-use hashbrown::HashMap;
+use crate::energy::EnergyMeter;
+use crate::messages::{Method, ModelConfig, Power, Success, SystemConfig, UserConfig};
+use crate::models::DiscoveredBulb;
+use crate::pilot::{FanMode, FanState, Pilot, PilotState};
+use crate::protocol::WizProtocol;
+use crate::push_manager::{PushEvent, PushManager};
+use crate::utils::normalize_mac;
+use crate::{Result, WizError};
+use parking_lot::RwLock;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info, instrument, warn};
 
+/// Handle to a single bulb, identified by its MAC.
+///
+/// With a locator attached (see [WizLight::with_locator]) a request the bulb
+/// does not answer makes the handle look up the bulb's current IP and retry
+/// once, so it survives DHCP lease changes.
 pub struct WizLight {
-    ip: String,
-    port: u32,
+    ip: RwLock<IpAddr>,
+    port: u16,
     mac: String,
-    bulb_type: BulbClass,
-    model_config: HashMap<String, String>,
-    white_range: Vec<f64>,
-    ext_white_range: Vec<f64>,
-    transport: Arc<BroadcastProtocol>,
+    bulb_type: RwLock<BulbClass>,
+    model_config: Option<ModelConfig>,
+    white_range: RwLock<Vec<f64>>,
+    ext_white_range: RwLock<Vec<f64>>,
+    transport: Arc<WizProtocol>,
+    locator: Option<Arc<BroadcastProtocol>>,
+    push: Option<Arc<PushManager>>,
+}
+
+/// How long [WizLight::reboot] waits for the bulb to come back.
+pub const RESTART_TIMEOUT: f64 = 60.0;
+/// How long a bulb may keep answering after accepting a restart command.
+const RESTART_GRACE: f64 = 5.0;
+/// Timeout of each poll while waiting for a restart.
+const RESTART_POLL: f64 = 1.0;
+/// Time in seconds a socket gets to answer getPower while being classified.
+const POWER_PROBE_TIMEOUT: f64 = 1.0;
+
+/// Explicit consent to wipe the bulb with a given MAC.
+///
+/// [WizLight::factory_reset] only proceeds when the MAC matches its bulb, so
+/// a confirmation made for one bulb cannot reset another.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResetConfirmation {
+    mac: String,
+}
+
+impl ResetConfirmation {
+    /// Confirm resetting the bulb with `mac`, in any common notation.
+    pub fn new(mac: &str) -> Self {
+        Self {
+            mac: normalize_mac(mac),
+        }
+    }
+    pub fn mac(&self) -> &str {
+        &self.mac
+    }
+}
+
+/// Everything learned about a bulb from its configuration queries.
+pub(crate) struct BulbInfo {
+    pub system_config: SystemConfig,
+    pub model_config: Option<ModelConfig>,
+    pub bulb_type: BulbClass,
+    pub white_range: Vec<f64>,
+    pub ext_white_range: Vec<f64>,
+}
+
+fn kelvins(range: &[u32]) -> Vec<f64> {
+    range.iter().map(|&k| k as f64).collect()
+}
+
+/// Kelvin the class accepts: the extended range when known, else the white range.
+fn kelvin_list(white_range: &[f64], ext_white_range: &[f64]) -> Option<Vec<f64>> {
+    [ext_white_range, white_range]
+        .into_iter()
+        .find(|r| !r.is_empty())
+        .map(<[f64]>::to_vec)
+}
+
+/// Whether the socket at `ip` meters power, which not every module name tells.
+///
+/// Any failure, or no answer within [POWER_PROBE_TIMEOUT], counts as no power
+/// monitoring.
+async fn answers_get_power(transport: &WizProtocol, ip: IpAddr) -> bool {
+    let call = transport.call::<_, Power>(ip, Method::GetPower, serde_json::json!({}));
+    match tokio::time::timeout(Duration::from_secs_f64(POWER_PROBE_TIMEOUT), call).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(e)) => {
+            debug!("{} does not answer getPower: {e}", ip);
+            false
+        }
+        Err(_) => {
+            debug!("{} did not answer getPower in time", ip);
+            false
+        }
+    }
+}
+
+/// Query getSystemConfig and getModelConfig and classify the bulb from the answers.
+#[instrument(skip(transport))]
+pub(crate) async fn probe(transport: &WizProtocol, ip: IpAddr) -> Result<BulbInfo> {
+    let system_config = transport
+        .call(ip, Method::GetSystemConfig, serde_json::json!({}))
+        .await?;
+    probe_with(transport, ip, system_config).await
+}
+
+/// Like [probe], for a bulb whose `system_config` was already queried.
+#[instrument(skip(transport, system_config))]
+pub(crate) async fn probe_with(
+    transport: &WizProtocol,
+    ip: IpAddr,
+    system_config: SystemConfig,
+) -> Result<BulbInfo> {
+    let empty = serde_json::json!({});
+    let model_config: Option<ModelConfig> =
+        match transport.call(ip, Method::GetModelConfig, &empty).await {
+            Ok(c) => Some(c),
+            Err(WizError::BulbErr(e)) if e.is_method_not_found() => {
+                debug!("{} does not support getModelConfig", ip);
+                None
+            }
+            Err(e) => return Err(e),
+        };
+    let mut white_range = Vec::new();
+    let mut ext_white_range = Vec::new();
+    if let Some(cct) = model_config.as_ref().and_then(|c| c.cct_range.as_ref()) {
+        match cct.as_slice() {
+            [ext_min, min, max, ext_max] => {
+                white_range = vec![*min, *max];
+                ext_white_range = vec![*ext_min, *ext_max];
+            }
+            [min, max] => {
+                white_range = vec![*min, *max];
+                ext_white_range = white_range.clone();
+            }
+            _ => {}
+        }
+    }
+    if let Some(r) = &system_config.white_range {
+        white_range = r.clone();
+    }
+    if let Some(r) = &system_config.ext_range {
+        ext_white_range = r.clone();
+    }
+    match transport
+        .call::<_, UserConfig>(ip, Method::GetUserConfig, &empty)
+        .await
+    {
+        Ok(user) => {
+            if let Some(r) = &user.white_range {
+                white_range = kelvins(r);
+            }
+            if let Some(r) = &user.ext_range {
+                ext_white_range = kelvins(r);
+            }
+        }
+        Err(WizError::BulbErr(e)) if e.is_method_not_found() => {
+            debug!("{} does not support getUserConfig", ip);
+        }
+        Err(e) => warn!("Cannot read the user config of {}: {e}", ip),
+    }
+    let mut bulb_type = BulbClass::from_data(
+        &system_config.module_name,
+        kelvin_list(&white_range, &ext_white_range),
+        system_config.fw_version.clone(),
+        model_config.as_ref().and_then(|c| c.nowc),
+        model_config.as_ref().and_then(|c| c.wcr),
+        model_config.as_ref().and_then(|c| c.fan_speed),
+    )?;
+    if let BulbClass::Socket(feat) = &mut bulb_type {
+        feat.power_monitoring = answers_get_power(transport, ip).await;
+    }
+    Ok(BulbInfo {
+        system_config,
+        model_config,
+        bulb_type,
+        white_range,
+        ext_white_range,
+    })
+}
+
+impl WizLight {
+    /// Connect to the bulb at `ip` over a transport of its own.
+    pub async fn connect(ip: &str) -> Result<Self> {
+        Self::connect_with(ip, Arc::new(WizProtocol::new()?)).await
+    }
+    /// Connect to the bulb at `ip` over a shared transport.
+    ///
+    /// Queries the bulb for its MAC, module and kelvin ranges.
+    #[instrument(skip(transport))]
+    pub async fn connect_with(ip: &str, transport: Arc<WizProtocol>) -> Result<Self> {
+        let ip: IpAddr = ip.parse()?;
+        let info = probe(&transport, ip).await?;
+        debug!("Connected to {} at {}", info.system_config.module_name, ip);
+        Ok(Self {
+            ip: RwLock::new(ip),
+            port: transport.port(),
+            mac: info.system_config.mac,
+            bulb_type: RwLock::new(info.bulb_type),
+            model_config: info.model_config,
+            white_range: RwLock::new(info.white_range),
+            ext_white_range: RwLock::new(info.ext_white_range),
+            transport,
+            locator: None,
+            push: None,
+        })
+    }
+    pub async fn from_discovered(bulb: DiscoveredBulb) -> Result<Self> {
+        Self::connect(&bulb.ip_address).await
+    }
+    /// Build from a discovered bulb over a shared transport.
+    ///
+    /// A bulb whose class and kelvin ranges are already known, e.g. from a
+    /// registry cache, is not queried again.
+    pub async fn from_discovered_with(
+        bulb: DiscoveredBulb,
+        transport: Arc<WizProtocol>,
+    ) -> Result<Self> {
+        let bulb_type = match bulb.class {
+            Some(class)
+                if class.features().kelvin_range.is_none() || !bulb.white_range.is_empty() =>
+            {
+                class
+            }
+            _ => return Self::connect_with(&bulb.ip_address, transport).await,
+        };
+        Ok(Self {
+            ip: RwLock::new(bulb.ip_address.parse()?),
+            port: transport.port(),
+            mac: bulb.mac_address,
+            bulb_type: RwLock::new(bulb_type),
+            model_config: bulb.model_config,
+            white_range: RwLock::new(bulb.white_range),
+            ext_white_range: RwLock::new(bulb.ext_white_range),
+            transport,
+            locator: None,
+            push: None,
+        })
+    }
+    /// Connect to the bulb with `mac`, looking it up through `locator`.
+    ///
+    /// The returned handle keeps `locator` to follow the bulb across IP changes.
+    pub async fn by_mac(
+        mac: &str,
+        locator: Arc<BroadcastProtocol>,
+        transport: Arc<WizProtocol>,
+    ) -> Result<Self> {
+        let bulb = match locator.reg.get(mac) {
+            Some(bulb) => bulb,
+            None => locator.locate(mac, None).await?,
+        };
+        Ok(Self::from_discovered_with((*bulb).clone(), transport)
+            .await?
+            .with_locator(locator))
+    }
+    /// Follow the bulb to its new IP through `locator` when it stops answering.
+    ///
+    /// The bulb is added to the locator's registry if it is not there yet.
+    pub fn with_locator(mut self, locator: Arc<BroadcastProtocol>) -> Self {
+        if !locator.reg.is_registered(&self.mac) {
+            let class = self.bulb_type();
+            let mut bulb = DiscoveredBulb::new(self.ip().to_string(), self.mac.clone());
+            bulb.module_name = Some(class.features().name.clone());
+            bulb.fw_version = class.features().fw_version.clone();
+            bulb.class = Some(class);
+            bulb.model_config = self.model_config.clone();
+            bulb.white_range = self.white_range();
+            bulb.ext_white_range = self.ext_white_range();
+            locator.reg.register(bulb);
+        }
+        self.locator = Some(locator);
+        self
+    }
+    /// Wait for the bulb's `firstBeat` on `push` when it restarts, instead of
+    /// only polling it.
+    pub fn with_push_manager(mut self, push: Arc<PushManager>) -> Self {
+        self.push = Some(push);
+        self
+    }
+    /// Send `method` with `params` to this bulb and return the typed result.
+    pub async fn call<P: Serialize, R: DeserializeOwned>(
+        &self,
+        method: Method,
+        params: P,
+    ) -> Result<R> {
+        let ip = self.ip();
+        match self.transport.call(ip, method.clone(), &params).await {
+            Err(WizError::NoResponse(..)) if self.locator.is_some() => {
+                let ip = self.relocate(ip).await?;
+                self.transport.call(ip, method, &params).await
+            }
+            res => res,
+        }
+    }
+    /// Look up the current IP of this bulb after `stale` stopped answering.
+    #[instrument(skip(self), fields(mac = %self.mac))]
+    async fn relocate(&self, stale: IpAddr) -> Result<IpAddr> {
+        let locator = self
+            .locator
+            .as_ref()
+            .ok_or_else(|| WizError::BulbNotFound(self.mac.clone()))?;
+        let bulb = locator.locate(&self.mac, Some(stale)).await?;
+        let ip: IpAddr = bulb.ip_address.parse()?;
+        if ip != stale {
+            info!("Bulb {} moved from {} to {}", self.mac, stale, ip);
+        }
+        *self.ip.write() = ip;
+        Ok(ip)
+    }
+    /// Read the current state of the bulb.
+    pub async fn get_pilot(&self) -> Result<PilotState> {
+        self.call(Method::GetPilot, serde_json::json!({})).await
+    }
+    /// Validate `pilot` against this bulb's class and send it with setPilot.
+    #[instrument(skip(self), fields(mac = %self.mac))]
+    pub async fn set_pilot(&self, pilot: &Pilot) -> Result<()> {
+        pilot.validate(&self.bulb_type.read())?;
+        let res: Success = self.call(Method::SetPilot, pilot).await?;
+        if !res.success {
+            return Err(WizError::CommandFailed(Method::SetPilot));
+        }
+        Ok(())
+    }
+    fn require_dual_head(&self) -> Result<()> {
+        let class = self.bulb_type.read();
+        let feat = class.features();
+        if !feat.dual_head {
+            return Err(WizError::Unsupported("dual-head ratio", feat.name.clone()));
+        }
+        Ok(())
+    }
+    /// Up/down light ratio of a dual-head fixture, 0-100.
+    pub async fn get_ratio(&self) -> Result<u8> {
+        self.require_dual_head()?;
+        self.get_pilot()
+            .await?
+            .ratio()
+            .ok_or(WizError::MissingField("ratio"))
+    }
+    /// Set the up/down light ratio of a dual-head fixture, 0-100.
+    pub async fn set_ratio(&self, ratio: u8) -> Result<()> {
+        self.require_dual_head()?;
+        self.set_pilot(&Pilot::builder().ratio(ratio).build()).await
+    }
+    /// Set brightness on the 0-255 scale and the up/down ratio in one command.
+    pub async fn set_brightness_and_ratio(&self, brightness: u8, ratio: u8) -> Result<()> {
+        self.require_dual_head()?;
+        let pilot = Pilot::builder().brightness(brightness).ratio(ratio).build();
+        self.set_pilot(&pilot).await
+    }
+    fn require_fan(&self) -> Result<()> {
+        let class = self.bulb_type.read();
+        let feat = class.features();
+        if !feat.fan {
+            return Err(WizError::Unsupported("fan", feat.name.clone()));
+        }
+        Ok(())
+    }
+    /// State of the fan; the light is read with [WizLight::get_pilot].
+    pub async fn get_fan(&self) -> Result<FanState> {
+        self.require_fan()?;
+        self.get_pilot()
+            .await?
+            .fan()
+            .ok_or(WizError::MissingField("fanState"))
+    }
+    pub async fn fan_turn_on(&self) -> Result<()> {
+        self.require_fan()?;
+        self.set_pilot(&Pilot::builder().fan_on(true).build()).await
+    }
+    pub async fn fan_turn_off(&self) -> Result<()> {
+        self.require_fan()?;
+        self.set_pilot(&Pilot::builder().fan_on(false).build())
+            .await
+    }
+    pub async fn fan_set_mode(&self, mode: FanMode) -> Result<()> {
+        self.require_fan()?;
+        self.set_pilot(&Pilot::builder().fan_mode(mode).build())
+            .await
+    }
+    /// Set the fan speed, from 1 to [Features::fan_speed_range](crate::bulblibrary::Features::fan_speed_range).
+    pub async fn fan_set_speed(&self, speed: u8) -> Result<()> {
+        self.require_fan()?;
+        self.set_pilot(&Pilot::builder().fan_speed(speed).build())
+            .await
+    }
+    pub async fn fan_set_reverse(&self, reverse: bool) -> Result<()> {
+        self.require_fan()?;
+        self.set_pilot(&Pilot::builder().fan_reverse(reverse).build())
+            .await
+    }
+    /// Current draw of a metering socket in watts.
+    pub async fn get_power(&self) -> Result<f64> {
+        {
+            let class = self.bulb_type.read();
+            let feat = class.features();
+            if !feat.power_monitoring {
+                return Err(WizError::Unsupported("power metering", feat.name.clone()));
+            }
+        }
+        let power: Power = self.call(Method::GetPower, serde_json::json!({})).await?;
+        Ok(power.watts())
+    }
+    /// Read the current draw and add it to `meter`, returning the watts read.
+    pub async fn sample_energy(&self, meter: &mut EnergyMeter) -> Result<f64> {
+        let watts = self.get_power().await?;
+        meter.add_sample(watts, Instant::now());
+        Ok(watts)
+    }
+    pub async fn get_user_config(&self) -> Result<UserConfig> {
+        self.call(Method::GetUserConfig, serde_json::json!({}))
+            .await
+    }
+    /// Validate and send `config`; unset fields are left as they are.
+    ///
+    /// White range overrides are applied to [WizLight::white_range],
+    /// [WizLight::ext_white_range] and the kelvin range of
+    /// [WizLight::bulb_type] once the bulb accepts them.
+    #[instrument(skip(self), fields(mac = %self.mac))]
+    pub async fn set_user_config(&self, config: &UserConfig) -> Result<()> {
+        config.validate()?;
+        let res: Success = self.call(Method::SetUserConfig, config).await?;
+        if !res.success {
+            return Err(WizError::CommandFailed(Method::SetUserConfig));
+        }
+        if let Some(r) = &config.white_range {
+            *self.white_range.write() = kelvins(r);
+        }
+        if let Some(r) = &config.ext_range {
+            *self.ext_white_range.write() = kelvins(r);
+        }
+        let list = kelvin_list(&self.white_range(), &self.ext_white_range());
+        if let Some(list) = list {
+            let min = list.iter().copied().fold(f64::INFINITY, f64::min);
+            let max = list.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            self.bulb_type.write().features_mut().kelvin_range = Some(KelvinRange::new(max, min));
+        }
+        Ok(())
+    }
+    /// Reboot the bulb and return how long it took to come back.
+    #[instrument(skip(self), fields(mac = %self.mac))]
+    pub async fn reboot(&self) -> Result<Duration> {
+        let timeout = Duration::from_secs_f64(RESTART_TIMEOUT);
+        let mut first_beat = self.push.as_ref().map(|p| p.subscribe_mac(&self.mac));
+        let started = Instant::now();
+        let deadline = tokio::time::Instant::now() + timeout;
+        self.send_restart(Method::Reboot).await?;
+        let back = async {
+            if let Some(sub) = first_beat.as_mut() {
+                loop {
+                    match sub.recv().await {
+                        Ok(push) if matches!(push.event, PushEvent::FirstBeat { .. }) => {
+                            return push.ip;
+                        }
+                        Ok(_) | Err(RecvError::Lagged(_)) => {}
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+            }
+            std::future::pending().await
+        };
+        let polled = async {
+            self.wait_silent(Method::Reboot).await?;
+            Ok::<_, WizError>(self.wait_back().await)
+        };
+        let ip = tokio::select! {
+            ip = back => ip,
+            ip = polled => ip?,
+            _ = tokio::time::sleep_until(deadline) => {
+                return Err(WizError::NotBack(self.mac.clone(), timeout));
+            }
+        };
+        *self.ip.write() = ip;
+        let took = started.elapsed();
+        info!("Bulb {} back at {} after {:?}", self.mac, ip, took);
+        Ok(took)
+    }
+    /// Reset the bulb to factory settings and return how long it took to go
+    /// silent.
+    ///
+    /// A reset bulb forgets its Wi-Fi settings and does not come back, so it
+    /// is not waited for. Fails with [WizError::ResetNotConfirmed] unless
+    /// `confirm` was made for this bulb's MAC, and with
+    /// [WizError::NotRestarted] if the bulb still answers after [RESTART_GRACE].
+    #[instrument(skip(self), fields(mac = %self.mac))]
+    pub async fn factory_reset(&self, confirm: ResetConfirmation) -> Result<Duration> {
+        if confirm.mac() != normalize_mac(&self.mac) {
+            return Err(WizError::ResetNotConfirmed(confirm.mac, self.mac.clone()));
+        }
+        let started = Instant::now();
+        self.send_restart(Method::Reset).await?;
+        self.wait_silent(Method::Reset).await?;
+        let took = started.elapsed();
+        info!("Bulb {} was reset after {:?}", self.mac, took);
+        Ok(took)
+    }
+    /// Send `method`, which the bulb acknowledges before restarting.
+    async fn send_restart(&self, method: Method) -> Result<()> {
+        let res: Success = self
+            .transport
+            .call(self.ip(), method.clone(), serde_json::json!({}))
+            .await?;
+        if !res.success {
+            return Err(WizError::CommandFailed(method));
+        }
+        Ok(())
+    }
+    /// Whether the bulb answers a poll within [RESTART_POLL].
+    async fn alive(&self) -> bool {
+        let poll = Duration::from_secs_f64(RESTART_POLL);
+        let pilot = self.transport.call::<_, PilotState>(
+            self.ip(),
+            Method::GetPilot,
+            serde_json::json!({}),
+        );
+        matches!(tokio::time::timeout(poll, pilot).await, Ok(Ok(_)))
+    }
+    /// Poll until the bulb stops answering after accepting `method`.
+    ///
+    /// Fails with [WizError::NotRestarted] if the bulb still answers after
+    /// [RESTART_GRACE].
+    async fn wait_silent(&self, method: Method) -> Result<()> {
+        let grace = Duration::from_secs_f64(RESTART_GRACE);
+        let silent_by = Instant::now() + grace;
+        while self.alive().await {
+            if Instant::now() >= silent_by {
+                return Err(WizError::NotRestarted(self.mac.clone(), method, grace));
+            }
+            tokio::time::sleep(Duration::from_secs_f64(RESTART_POLL / 2.0)).await;
+        }
+        debug!("Bulb {} went silent", self.mac);
+        Ok(())
+    }
+    /// Poll until the bulb answers again and return its IP.
+    async fn wait_back(&self) -> IpAddr {
+        while !self.alive().await {
+            tokio::time::sleep(Duration::from_secs_f64(RESTART_POLL / 2.0)).await;
+        }
+        self.ip()
+    }
+    pub async fn turn_on(&self) -> Result<()> {
+        self.set_pilot(&Pilot::on()).await
+    }
+    pub async fn turn_off(&self) -> Result<()> {
+        self.set_pilot(&Pilot::off()).await
+    }
+    /// IP the bulb was last reached at.
+    pub fn ip(&self) -> IpAddr {
+        *self.ip.read()
+    }
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+    pub fn mac(&self) -> &str {
+        &self.mac
+    }
+    pub fn bulb_type(&self) -> BulbClass {
+        self.bulb_type.read().clone()
+    }
+    pub fn model_config(&self) -> Option<&ModelConfig> {
+        self.model_config.as_ref()
+    }
+    /// `[min, max]` kelvin, including overrides from the user config.
+    pub fn white_range(&self) -> Vec<f64> {
+        self.white_range.read().clone()
+    }
+    pub fn ext_white_range(&self) -> Vec<f64> {
+        self.ext_white_range.read().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::DiscoveryConfig;
+    use crate::models::DiscoveryEvent;
+    use crate::testing::{class, method_not_found, ok, FakeBulb};
+    use serde_json::{json, Value};
+    use std::net::SocketAddr;
+
+    fn system_config(module: &str, extra: Value) -> Value {
+        let mut config =
+            json!({"mac": "a8bb50aabbcc", "moduleName": module, "fwVersion": "1.25.0"});
+        config
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        config
+    }
+
+    #[tokio::test]
+    async fn probe_kelvin_ranges() {
+        let cct4 = || ok(json!({"cctRange": [1000, 2200, 6500, 10000]}));
+        // (systemConfig extras, modelConfig, userConfig, white, extended, class range)
+        let cases = [
+            (
+                json!({}),
+                cct4(),
+                method_not_found(),
+                vec![2200.0, 6500.0],
+                vec![1000.0, 10000.0],
+                (1000.0, 10000.0),
+            ),
+            (
+                json!({}),
+                ok(json!({"cctRange": [2700, 6500]})),
+                method_not_found(),
+                vec![2700.0, 6500.0],
+                vec![2700.0, 6500.0],
+                (2700.0, 6500.0),
+            ),
+            (
+                json!({"whiteRange": [2500, 6000], "extRange": [2000, 7000]}),
+                cct4(),
+                method_not_found(),
+                vec![2500.0, 6000.0],
+                vec![2000.0, 7000.0],
+                (2000.0, 7000.0),
+            ),
+            (
+                json!({"whiteRange": [2700, 6500]}),
+                method_not_found(),
+                method_not_found(),
+                vec![2700.0, 6500.0],
+                vec![],
+                (2700.0, 6500.0),
+            ),
+            (
+                json!({"whiteRange": [2500, 6000]}),
+                cct4(),
+                ok(json!({"whiteRange": [3000, 5000], "extRange": [2500, 5500]})),
+                vec![3000.0, 5000.0],
+                vec![2500.0, 5500.0],
+                (2500.0, 5500.0),
+            ),
+        ];
+        for (system, model, user, white, ext, (min, max)) in cases {
+            let system = system_config("ESP01_SHRGB1C_31", system);
+            let bulb = FakeBulb::spawn("127.0.0.1:0", move |req| match req["method"].as_str()? {
+                "getSystemConfig" => ok(system.clone()),
+                "getModelConfig" => model.clone(),
+                "getUserConfig" => user.clone(),
+                _ => None,
+            })
+            .await;
+            let info = probe(&bulb.transport(), bulb.ip()).await.unwrap();
+            assert_eq!(info.white_range, white);
+            assert_eq!(info.ext_white_range, ext);
+            assert_eq!(
+                info.bulb_type.features().kelvin_range,
+                Some(KelvinRange::new(max, min)),
+                "{white:?} {ext:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn probe_power_monitoring() {
+        for (power, expected) in [
+            (ok(json!({"power": 12500})), true),
+            (method_not_found(), false),
+            (None, false),
+        ] {
+            let bulb = FakeBulb::spawn("127.0.0.1:0", move |req| match req["method"].as_str()? {
+                "getSystemConfig" => ok(system_config("ESP10_SOCKET_06", json!({}))),
+                "getPower" => power.clone(),
+                _ => method_not_found(),
+            })
+            .await;
+            let start = Instant::now();
+            let info = probe(&bulb.transport(), bulb.ip()).await.unwrap();
+            assert_eq!(info.bulb_type.features().power_monitoring, expected);
+            assert!(start.elapsed() < Duration::from_secs_f64(POWER_PROBE_TIMEOUT + 1.0));
+            assert!(bulb.received("getPower") > 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn warm_start_keeps_cached_ranges() {
+        let fake = FakeBulb::spawn("127.0.0.1:0", |req| match req["method"].as_str()? {
+            "getSystemConfig" => ok(system_config("ESP01_SHRGB1C_31", json!({}))),
+            "getModelConfig" => ok(json!({"cctRange": [1000, 2200, 6500, 10000]})),
+            _ => method_not_found(),
+        })
+        .await;
+        let transport = Arc::new(fake.transport());
+        let mut cached = DiscoveredBulb::new(fake.ip().to_string(), "a8bb50aabbcc".to_string());
+        cached.class = Some(class("ESP01_SHRGB1C_31"));
+        cached.model_config =
+            serde_json::from_value(json!({"cctRange": [2000, 2700, 6500, 7000]})).ok();
+        cached.white_range = vec![2700.0, 6500.0];
+        cached.ext_white_range = vec![2000.0, 7000.0];
+
+        let light = WizLight::from_discovered_with(cached.clone(), transport.clone())
+            .await
+            .unwrap();
+        assert_eq!(fake.received("getSystemConfig"), 0);
+        assert_eq!(light.white_range(), [2700.0, 6500.0]);
+        assert_eq!(light.ext_white_range(), [2000.0, 7000.0]);
+        assert_eq!(light.model_config(), cached.model_config.as_ref());
+
+        // A cache written before the ranges were persisted is probed again.
+        cached.white_range.clear();
+        cached.ext_white_range.clear();
+        cached.model_config = None;
+        let light = WizLight::from_discovered_with(cached, transport)
+            .await
+            .unwrap();
+        assert_eq!(fake.received("getSystemConfig"), 1);
+        assert_eq!(light.white_range(), [2200.0, 6500.0]);
+        assert_eq!(light.ext_white_range(), [1000.0, 10000.0]);
+    }
+
+    #[tokio::test]
+    async fn set_user_config_updates_ranges() {
+        let fake = FakeBulb::spawn("127.0.0.1:0", |req| match req["method"].as_str()? {
+            "setUserConfig" => ok(json!({"success": true})),
+            _ => method_not_found(),
+        })
+        .await;
+        let mut cached = DiscoveredBulb::new(fake.ip().to_string(), "a8bb50aabbcc".to_string());
+        cached.class = Some(class("ESP01_SHRGB1C_31"));
+        cached.white_range = vec![2200.0, 6500.0];
+        let light = WizLight::from_discovered_with(cached, Arc::new(fake.transport()))
+            .await
+            .unwrap();
+
+        let config = UserConfig::update_builder()
+            .white_range((2700u32, 6000u32))
+            .build();
+        light.set_user_config(&config).await.unwrap();
+        assert_eq!(light.white_range(), [2700.0, 6000.0]);
+        assert!(light.ext_white_range().is_empty());
+        assert_eq!(
+            light.bulb_type().features().kelvin_range,
+            Some(KelvinRange::new(6000.0, 2700.0))
+        );
+
+        let config = UserConfig::update_builder()
+            .ext_range((2000u32, 7000u32))
+            .build();
+        light.set_user_config(&config).await.unwrap();
+        assert_eq!(light.white_range(), [2700.0, 6000.0]);
+        assert_eq!(light.ext_white_range(), [2000.0, 7000.0]);
+        assert_eq!(
+            light.bulb_type().features().kelvin_range,
+            Some(KelvinRange::new(7000.0, 2000.0))
+        );
+
+        let invalid = UserConfig::update_builder().min_dimming(101u8).build();
+        assert!(light.set_user_config(&invalid).await.is_err());
+        assert_eq!(fake.received("setUserConfig"), 2);
+    }
+
+    /// A light for `fake` whose details are cached, so nothing is probed.
+    async fn cached_light(fake: &FakeBulb) -> WizLight {
+        let mut cached = DiscoveredBulb::new(fake.ip().to_string(), "a8bb50aabbcc".to_string());
+        cached.class = Some(class("ESP01_SHRGB1C_31"));
+        cached.white_range = vec![2200.0, 6500.0];
+        WizLight::from_discovered_with(cached, Arc::new(fake.transport()))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn factory_reset_needs_the_bulbs_mac() {
+        let fake = FakeBulb::spawn("127.0.0.1:0", |_| ok(json!({"success": true}))).await;
+        let light = cached_light(&fake).await;
+        match light
+            .factory_reset(ResetConfirmation::new("a8:bb:50:00:00:01"))
+            .await
+        {
+            Err(WizError::ResetNotConfirmed(confirmed, mac)) => {
+                assert_eq!(confirmed, "a8bb50000001");
+                assert_eq!(mac, "a8bb50aabbcc");
+            }
+            other => panic!("expected an unconfirmed reset, got {other:?}"),
+        }
+        assert_eq!(fake.received("reset"), 0);
+    }
+
+    #[tokio::test]
+    async fn factory_reset_succeeds_once_silent() {
+        let fake = FakeBulb::spawn("127.0.0.1:0", |req| match req["method"].as_str()? {
+            "reset" => ok(json!({"success": true})),
+            _ => None,
+        })
+        .await;
+        let light = cached_light(&fake).await;
+        let took = light
+            .factory_reset(ResetConfirmation::new("A8:BB:50:AA:BB:CC"))
+            .await
+            .unwrap();
+        assert!(took < Duration::from_secs_f64(RESTART_GRACE));
+        assert_eq!(fake.received("reset"), 1);
+    }
+
+    #[tokio::test]
+    async fn reboot_waits_for_silence_and_return() {
+        let mut rebooted: Option<Instant> = None;
+        let fake = FakeBulb::spawn("127.0.0.1:0", move |req| match req["method"].as_str()? {
+            "reboot" => {
+                rebooted = Some(Instant::now());
+                ok(json!({"success": true}))
+            }
+            "getPilot" if rebooted.is_some_and(|t| t.elapsed() < Duration::from_millis(1500)) => {
+                None
+            }
+            "getPilot" => ok(json!({"mac": "a8bb50aabbcc", "state": true})),
+            _ => None,
+        })
+        .await;
+        let light = cached_light(&fake).await;
+        let took = light.reboot().await.unwrap();
+        assert!(took >= Duration::from_millis(1500), "{took:?}");
+        assert!(took < Duration::from_secs_f64(RESTART_TIMEOUT));
+        assert_eq!(light.ip(), fake.ip());
+    }
+
+    #[tokio::test]
+    async fn reboot_fails_if_the_bulb_keeps_answering() {
+        let fake = FakeBulb::spawn("127.0.0.1:0", |req| match req["method"].as_str()? {
+            "reboot" => ok(json!({"success": true})),
+            "getPilot" => ok(json!({"mac": "a8bb50aabbcc", "state": true})),
+            _ => None,
+        })
+        .await;
+        let light = cached_light(&fake).await;
+        match light.reboot().await {
+            Err(WizError::NotRestarted(mac, Method::Reboot, _)) => assert_eq!(mac, "a8bb50aabbcc"),
+            other => panic!("expected the bulb not to restart, got {other:?}"),
+        }
+    }
+
+    /// Needs a second loopback address, which only Linux routes by default.
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn relocates_and_retries_after_a_move() {
+        let mac = "a8bb50aabbcc";
+        let old = FakeBulb::spawn("127.0.0.1:0", |_| None).await;
+        let new = FakeBulb::spawn(("127.0.0.2", old.port()), move |req| {
+            match req["method"].as_str()? {
+                "registration" => ok(json!({"mac": mac, "success": true})),
+                "getPilot" => ok(json!({"mac": mac, "state": true, "dimming": 50})),
+                _ => None,
+            }
+        })
+        .await;
+        let config = DiscoveryConfig::builder()
+            .timeout(Duration::from_secs(2))
+            .broadcast_addr(SocketAddr::new(new.ip(), new.port()))
+            .build();
+        let locator = Arc::new(BroadcastProtocol::with_config(config).unwrap());
+        let mut events = locator.subscribe();
+        let transport = Arc::new(old.transport().with_timeout(Duration::from_millis(300)));
+        let mut bulb = DiscoveredBulb::new(old.ip().to_string(), mac.to_string());
+        bulb.class = Some(class("ESP01_SHRGB1C_31"));
+        bulb.white_range = vec![2200.0, 6500.0];
+        let light = WizLight::from_discovered_with(bulb, transport)
+            .await
+            .unwrap()
+            .with_locator(locator.clone());
+
+        let state = light.get_pilot().await.unwrap();
+        assert!(state.is_on());
+        assert!(old.received("getPilot") > 0);
+        assert_eq!(light.ip(), new.ip());
+        assert_eq!(locator.reg.get(mac).unwrap().ip_address, "127.0.0.2");
+        match events.try_recv() {
+            Ok(DiscoveryEvent::IpChanged { bulb, old_ip }) => {
+                assert_eq!(old_ip, "127.0.0.1");
+                assert_eq!(bulb.ip_address, "127.0.0.2");
+            }
+            other => panic!("expected an IP change, got {other:?}"),
+        }
+    }
 }
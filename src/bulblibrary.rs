@@ -1,9 +1,11 @@
+use crate::scenes::{DW_SCENES, SCENES, TW_SCENES};
 use crate::{Result, WizError};
 use buildstructor::buildstructor;
+use serde::{Deserialize, Serialize};
 
 use rayon::prelude::*;
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Features {
     pub color: bool,
     pub color_tmp: bool,
@@ -15,8 +17,25 @@ pub struct Features {
     pub fw_version: Option<String>,
     pub white_channels: Option<i64>,
     pub white_to_color_ratio: Option<i64>,
+    #[serde(default)]
+    pub fan: bool,
+    /// Highest fan speed, speeds start at 1.
+    #[serde(default)]
+    pub fan_speed_range: Option<u8>,
+    /// Fan can spin in reverse.
+    #[serde(default)]
+    pub fan_reverse: bool,
+    /// Fan has the breeze mode.
+    #[serde(default)]
+    pub fan_breeze_mode: bool,
+    /// Answers `getPower`.
+    #[serde(default)]
+    pub power_monitoring: bool,
 }
 
+/// Fan speeds assumed when the fan does not report `fanSpeed`.
+pub const DEFAULT_FAN_SPEEDS: u8 = 6;
+
 #[buildstructor]
 impl Features {
     #[builder]
@@ -40,6 +59,7 @@ impl Features {
             white_channels,
             white_to_color_ratio,
             kelvin_range,
+            ..Default::default()
         }
     }
     #[builder]
@@ -63,6 +83,7 @@ impl Features {
             white_channels,
             white_to_color_ratio,
             kelvin_range,
+            ..Default::default()
         }
     }
     #[builder]
@@ -86,9 +107,11 @@ impl Features {
             white_channels,
             white_to_color_ratio,
             kelvin_range,
+            ..Default::default()
         }
     }
     #[builder]
+    #[allow(clippy::too_many_arguments)]
     pub fn sock_new(
         name: String,
         fw_version: Option<String>,
@@ -97,11 +120,13 @@ impl Features {
         white_channels: Option<i64>,
         white_to_color_ratio: Option<i64>,
         kelvin_range: Option<KelvinRange>,
+        power_monitoring: Option<bool>,
     ) -> Self {
         Self {
             color: false,
             color_tmp: false,
             brightness: false,
+            power_monitoring: power_monitoring.unwrap_or(false),
             name,
             fw_version,
             effect,
@@ -109,11 +134,46 @@ impl Features {
             white_channels,
             white_to_color_ratio,
             kelvin_range,
+            ..Default::default()
+        }
+    }
+    /// Ceiling fan with a dimmable light.
+    ///
+    /// Reverse and breeze mode default to supported, as on every FANDIMS module.
+    #[builder]
+    #[allow(clippy::too_many_arguments)]
+    pub fn fan_new(
+        name: String,
+        fw_version: Option<String>,
+        dual_head: bool,
+        white_channels: Option<i64>,
+        white_to_color_ratio: Option<i64>,
+        kelvin_range: Option<KelvinRange>,
+        fan_speed_range: Option<u8>,
+        fan_reverse: Option<bool>,
+        fan_breeze_mode: Option<bool>,
+    ) -> Self {
+        Self {
+            color: false,
+            color_tmp: false,
+            brightness: true,
+            effect: false,
+            name,
+            fw_version,
+            dual_head,
+            white_channels,
+            white_to_color_ratio,
+            kelvin_range,
+            fan: true,
+            fan_speed_range: Some(fan_speed_range.unwrap_or(DEFAULT_FAN_SPEEDS)),
+            fan_reverse: fan_reverse.unwrap_or(true),
+            fan_breeze_mode: fan_breeze_mode.unwrap_or(true),
+            power_monitoring: false,
         }
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct KelvinRange {
     max: f64,
     min: f64,
@@ -123,8 +183,18 @@ impl KelvinRange {
     pub fn new(max: f64, min: f64) -> Self {
         Self { max, min }
     }
+    pub fn max(&self) -> f64 {
+        self.max
+    }
+    pub fn min(&self) -> f64 {
+        self.min
+    }
+    pub fn contains(&self, kelvin: f64) -> bool {
+        (self.min..=self.max).contains(&kelvin)
+    }
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum BulbClass {
     /// Tunable White
     ///
@@ -142,15 +212,57 @@ pub enum BulbClass {
     ///
     /// Smart socket with only on/off.
     Socket(Features),
+    /// Fan
+    ///
+    /// Ceiling fan with a dimmable light.
+    Fan(Features),
 }
 
 impl BulbClass {
+    pub fn features(&self) -> &Features {
+        match self {
+            BulbClass::TW(f)
+            | BulbClass::DW(f)
+            | BulbClass::Rgb(f)
+            | BulbClass::Socket(f)
+            | BulbClass::Fan(f) => f,
+        }
+    }
+    pub(crate) fn features_mut(&mut self) -> &mut Features {
+        match self {
+            BulbClass::TW(f)
+            | BulbClass::DW(f)
+            | BulbClass::Rgb(f)
+            | BulbClass::Socket(f)
+            | BulbClass::Fan(f) => f,
+        }
+    }
+    /// Short name of the class: `RGB`, `TW`, `DW`, `Socket` or `Fan`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BulbClass::TW(_) => "TW",
+            BulbClass::DW(_) => "DW",
+            BulbClass::Rgb(_) => "RGB",
+            BulbClass::Socket(_) => "Socket",
+            BulbClass::Fan(_) => "Fan",
+        }
+    }
+    /// Whether the bulb can run the scene with `id`.
+    pub fn supports_scene(&self, id: u32) -> bool {
+        match self {
+            BulbClass::Rgb(_) => SCENES.contains_key(&id),
+            BulbClass::TW(_) => TW_SCENES.contains(&id),
+            BulbClass::DW(_) | BulbClass::Fan(_) => DW_SCENES.contains(&id),
+            BulbClass::Socket(_) => false,
+        }
+    }
     pub fn from_data(
         module_name: &str,
         kelvin_list: Option<Vec<f64>>,
         fw_version: Option<String>,
         white_channels: Option<i64>,
         white_to_color_ratio: Option<i64>,
+        fan_speed_range: Option<u8>,
     ) -> Result<Self> {
         let ident = module_name
             .split('_')
@@ -166,7 +278,18 @@ impl BulbClass {
             None
         };
         let bulb_type = {
-            if ident.contains("RGB") {
+            if ident.contains("FANDIMS") {
+                let feat = Features::fan_builder()
+                    .dual_head(dual)
+                    .and_fw_version(fw_version)
+                    .and_white_channels(white_channels)
+                    .and_white_to_color_ratio(white_to_color_ratio)
+                    .and_kelvin_range(k_range)
+                    .and_fan_speed_range(fan_speed_range)
+                    .name(module_name)
+                    .build();
+                BulbClass::Fan(feat)
+            } else if ident.contains("RGB") {
                 let feat = Features::rgb_builder()
                     .dual_head(dual)
                     .effect(true)
@@ -216,3 +339,50 @@ impl BulbClass {
         Ok(bulb_type)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::class;
+
+    #[test]
+    fn classify_module_names() {
+        let cases = [
+            ("ESP01_SHRGB1C_31", "RGB"),
+            ("ESP01_DHRGB1C_31", "RGB"),
+            ("ESP01_SHTW1C_31", "TW"),
+            ("ESP06_SHDW9_01", "DW"),
+            ("ESP10_SOCKET_06", "Socket"),
+            ("ESP20_FANDIMS_31", "Fan"),
+            ("ESP03_FANDIMS_41", "Fan"),
+        ];
+        for (module, kind) in cases {
+            assert_eq!(class(module).kind(), kind, "{module}");
+        }
+        assert!(BulbClass::from_data("ESP01", None, None, None, None, None).is_err());
+    }
+
+    #[test]
+    fn fan_features() {
+        let fan = class("ESP20_FANDIMS_31");
+        assert!(matches!(fan, BulbClass::Fan(_)));
+        let feat = fan.features();
+        assert!(feat.fan && feat.brightness);
+        assert!(!feat.color && !feat.color_tmp);
+        assert_eq!(feat.fan_speed_range, Some(DEFAULT_FAN_SPEEDS));
+        assert!(feat.fan_reverse && feat.fan_breeze_mode);
+        let feat = BulbClass::from_data("ESP20_FANDIMS_31", None, None, None, None, Some(4))
+            .unwrap()
+            .features()
+            .clone();
+        assert_eq!(feat.fan_speed_range, Some(4));
+        assert!(!class("ESP06_SHDW9_01").features().fan);
+    }
+
+    #[test]
+    fn dual_head_and_power_monitoring() {
+        assert!(class("ESP01_DHRGB1C_31").features().dual_head);
+        assert!(!class("ESP01_SHRGB1C_31").features().dual_head);
+        assert!(!class("ESP10_SOCKET_06").features().power_monitoring);
+    }
+}
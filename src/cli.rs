@@ -0,0 +1,334 @@
+use crate::bulb::{ResetConfirmation, WizLight};
+use crate::discovery::{BroadcastProtocol, DiscoveryConfig, DEFAULT_SWEEP_CONCURRENCY};
+use crate::messages::{Request, UserConfig};
+use crate::models::BulbRegistry;
+use crate::pilot::Pilot;
+use crate::protocol::WizProtocol;
+use crate::scenes::SCENES;
+use crate::utils::normalize_mac;
+use crate::{Result, WizError};
+
+use clap::{Parser, Subcommand};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tracing::instrument;
+
+/// Control WiZ bulbs on the local network.
+#[derive(Debug, Parser)]
+#[command(name = "wizlight", version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Discover bulbs on the local network.
+    Discover {
+        /// Probe every host of this CIDR range by unicast instead of broadcasting.
+        #[arg(long, value_name = "CIDR")]
+        scan: Option<String>,
+    },
+    /// Print the current state of a bulb.
+    State {
+        /// IP, MAC or module name of the bulb.
+        target: String,
+    },
+    /// Turn a bulb on.
+    On { target: String },
+    /// Turn a bulb off.
+    Off { target: String },
+    /// Change brightness, color, color temperature or scene.
+    Set {
+        target: String,
+        /// Brightness on the 0-255 scale.
+        #[arg(long)]
+        brightness: Option<u8>,
+        /// Color as `r,g,b` or `#rrggbb`.
+        #[arg(long, value_parser = parse_rgb)]
+        rgb: Option<(u8, u8, u8)>,
+        /// Color temperature in kelvin.
+        #[arg(long)]
+        kelvin: Option<u32>,
+        /// Scene id or name, see `wizlight scenes`.
+        #[arg(long, value_parser = parse_scene)]
+        scene: Option<u32>,
+        /// Effect speed, 10-200.
+        #[arg(long)]
+        speed: Option<u8>,
+        /// Up/down light ratio of dual-head fixtures, 0-100.
+        #[arg(long)]
+        ratio: Option<u8>,
+    },
+    /// List the known scenes.
+    Scenes,
+    /// Print the user config of a bulb, or change it when options are given.
+    Config {
+        target: String,
+        /// Fade-in duration in milliseconds.
+        #[arg(long)]
+        fade_in: Option<u32>,
+        /// Fade-out duration in milliseconds.
+        #[arg(long)]
+        fade_out: Option<u32>,
+        /// Restore the last state after a power cut instead of turning on fully.
+        #[arg(long)]
+        power_on_restore: Option<bool>,
+        /// Lowest brightness in percent.
+        #[arg(long)]
+        min_dimming: Option<u8>,
+    },
+    /// Reboot a bulb and wait for it to come back.
+    Reboot { target: String },
+    /// Reset a bulb to factory settings.
+    Reset {
+        target: String,
+        /// MAC of the bulb, repeated to confirm the reset.
+        #[arg(long)]
+        confirm: String,
+    },
+    /// Send any method to a bulb and print the raw reply.
+    Raw {
+        target: String,
+        method: String,
+        /// JSON params, `{}` when omitted.
+        params: Option<String>,
+    },
+}
+
+fn parse_rgb(s: &str) -> std::result::Result<(u8, u8, u8), String> {
+    let err = || format!("{s} is not r,g,b or #rrggbb");
+    if let Some(hex) = s.strip_prefix('#') {
+        if hex.len() != 6 {
+            return Err(err());
+        }
+        let ch = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| err());
+        return Ok((ch(0)?, ch(2)?, ch(4)?));
+    }
+    let parts = s
+        .split(',')
+        .map(|p| p.trim().parse::<u8>().map_err(|_| err()))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    match parts.as_slice() {
+        [r, g, b] => Ok((*r, *g, *b)),
+        _ => Err(err()),
+    }
+}
+
+fn parse_scene(s: &str) -> std::result::Result<u32, String> {
+    if let Ok(id) = s.parse::<u32>() {
+        return Ok(id);
+    }
+    SCENES
+        .iter()
+        .find(|(_, name)| name.eq_ignore_ascii_case(s))
+        .map(|(id, _)| *id)
+        .ok_or_else(|| format!("Unknown scene {s}"))
+}
+
+/// `target` as a bare lowercase MAC if it is written as one, e.g. `A8:BB:50:AA:BB:CC`.
+fn as_mac(target: &str) -> Option<String> {
+    let separators_only = target
+        .chars()
+        .all(|c| c.is_ascii_hexdigit() || matches!(c, ':' | '-' | '.'));
+    let mac = normalize_mac(target);
+    (separators_only && mac.len() == 12).then_some(mac)
+}
+
+async fn discover() -> Result<Arc<BulbRegistry>> {
+    let config = DiscoveryConfig::builder().enrich(true).build();
+    let proto = BroadcastProtocol::with_config(config)?;
+    proto.discover().await?;
+    Ok(proto.reg.clone())
+}
+
+/// Find the bulb `target` refers to: an IP, a MAC or a module name.
+///
+/// A MAC is looked up until its bulb answers; only module names need every
+/// bulb's system config.
+#[instrument(skip(transport))]
+async fn resolve(target: &str, transport: &Arc<WizProtocol>) -> Result<WizLight> {
+    if target.parse::<IpAddr>().is_ok() {
+        return WizLight::connect_with(target, transport.clone()).await;
+    }
+    let bulb = match as_mac(target) {
+        Some(mac) => {
+            let proto = BroadcastProtocol::with_config(DiscoveryConfig::default())?;
+            proto.locate(&mac, None).await?
+        }
+        None => discover()
+            .await?
+            .get_by_name(target)?
+            .ok_or_else(|| WizError::BulbNotFound(target.to_string()))?,
+    };
+    WizLight::from_discovered_with((*bulb).clone(), transport.clone()).await
+}
+
+pub async fn run(cli: Cli) -> Result<()> {
+    let transport = Arc::new(WizProtocol::new()?);
+    match cli.command {
+        Command::Discover { scan } => {
+            let reg = match scan {
+                Some(network) => {
+                    let config = DiscoveryConfig::builder().enrich(true).build();
+                    let proto = BroadcastProtocol::with_config(config)?;
+                    proto.sweep(&network, DEFAULT_SWEEP_CONCURRENCY).await?;
+                    proto.reg.clone()
+                }
+                None => discover().await?,
+            };
+            for bulb in reg.bulbs() {
+                println!("{}\t{}\t{}", bulb.ip_address, bulb.mac_address, bulb);
+            }
+        }
+        Command::State { target } => {
+            let light = resolve(&target, &transport).await?;
+            let state = light.get_pilot().await?;
+            println!("{}", serde_json::to_string_pretty(&state)?);
+        }
+        Command::On { target } => resolve(&target, &transport).await?.turn_on().await?,
+        Command::Off { target } => resolve(&target, &transport).await?.turn_off().await?,
+        Command::Set {
+            target,
+            brightness,
+            rgb,
+            kelvin,
+            scene,
+            speed,
+            ratio,
+        } => {
+            let pilot = Pilot::builder()
+                .and_brightness(brightness)
+                .and_rgb(rgb)
+                .and_color_temp(kelvin)
+                .and_scene(scene)
+                .and_speed(speed)
+                .and_ratio(ratio)
+                .build();
+            resolve(&target, &transport)
+                .await?
+                .set_pilot(&pilot)
+                .await?;
+        }
+        Command::Scenes => {
+            let mut scenes: Vec<_> = SCENES.iter().collect();
+            scenes.sort_by_key(|(id, _)| **id);
+            for (id, name) in scenes {
+                println!("{id}\t{name}");
+            }
+        }
+        Command::Config {
+            target,
+            fade_in,
+            fade_out,
+            power_on_restore,
+            min_dimming,
+        } => {
+            let light = resolve(&target, &transport).await?;
+            let update = UserConfig::update_builder()
+                .and_fade_in(fade_in)
+                .and_fade_out(fade_out)
+                .and_po(power_on_restore)
+                .and_min_dimming(min_dimming)
+                .build();
+            if update == UserConfig::default() {
+                let config = light.get_user_config().await?;
+                println!("{}", serde_json::to_string_pretty(&config)?);
+            } else {
+                light.set_user_config(&update).await?;
+            }
+        }
+        Command::Reboot { target } => {
+            let took = resolve(&target, &transport).await?.reboot().await?;
+            println!("Back after {:.1}s", took.as_secs_f64());
+        }
+        Command::Reset { target, confirm } => {
+            let light = resolve(&target, &transport).await?;
+            let took = light
+                .factory_reset(ResetConfirmation::new(&confirm))
+                .await?;
+            println!("Reset after {:.1}s", took.as_secs_f64());
+        }
+        Command::Raw {
+            target,
+            method,
+            params,
+        } => {
+            let ip = match target.parse::<IpAddr>() {
+                Ok(ip) => ip,
+                Err(_) => resolve(&target, &transport).await?.ip(),
+            };
+            let params: serde_json::Value =
+                serde_json::from_str(params.as_deref().unwrap_or("{}"))?;
+            let req = Request::new(method.parse().unwrap_or_else(|e| match e {}), params);
+            let resp = transport.request(ip, &req).await?;
+            println!("{}", serde_json::to_string_pretty(&resp)?);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rgb_arguments() {
+        let cases = [
+            ("#ff8000", Some((255, 128, 0))),
+            ("#FF8000", Some((255, 128, 0))),
+            ("255,128,0", Some((255, 128, 0))),
+            ("255, 128, 0", Some((255, 128, 0))),
+            ("#fff", None),
+            ("#ff80000", None),
+            ("#gg8000", None),
+            ("ff8000", None),
+            ("255,128", None),
+            ("255,128,0,0", None),
+            ("256,128,0", None),
+            ("", None),
+        ];
+        for (arg, expected) in cases {
+            assert_eq!(parse_rgb(arg).ok(), expected, "{arg}");
+        }
+        assert_eq!(
+            parse_rgb("#fff").unwrap_err(),
+            "#fff is not r,g,b or #rrggbb"
+        );
+    }
+
+    #[test]
+    fn scene_arguments() {
+        let cases = [
+            ("1", Some(1)),
+            ("1000", Some(1000)),
+            ("Ocean", Some(1)),
+            ("ocean", Some(1)),
+            ("warm white", Some(11)),
+            ("TV time", Some(18)),
+            ("Oceans", None),
+            ("", None),
+            ("-1", None),
+        ];
+        for (arg, expected) in cases {
+            assert_eq!(parse_scene(arg).ok(), expected, "{arg}");
+        }
+        assert_eq!(parse_scene("Oceans").unwrap_err(), "Unknown scene Oceans");
+    }
+
+    #[test]
+    fn mac_targets() {
+        let cases = [
+            ("a8bb50aabbcc", Some("a8bb50aabbcc")),
+            ("A8:BB:50:AA:BB:CC", Some("a8bb50aabbcc")),
+            ("a8-bb-50-aa-bb-cc", Some("a8bb50aabbcc")),
+            ("a8bb.50aa.bbcc", Some("a8bb50aabbcc")),
+            ("a8bb50aabb", None),
+            ("ESP01_SHRGB1C_31", None),
+            ("bedroom a8bb50aabbcc", None),
+        ];
+        for (target, expected) in cases {
+            assert_eq!(as_mac(target).as_deref(), expected, "{target}");
+        }
+    }
+}
@@ -1,47 +1,292 @@
-use crate::models::{BulbRegistry, DiscoveredBulb, RegistrationMessage};
-use crate::utils::{create_udp_broadcast, get_local_adddrs};
+use crate::bulb::{probe, probe_with, BulbInfo};
+use crate::messages::{Method, RegistrationParams, Request, SystemConfig};
+use crate::models::{
+    BulbRegistry, DiscoveredBulb, DiscoveryEvent, RegistrationMessage, RegistryEvent,
+};
+use crate::protocol::WizProtocol;
+use crate::utils::{
+    create_udp_bound, get_broadcast_nets, get_interface_addr, get_local_adddrs, InterfaceNet,
+};
 
 use crate::{Result, WizError};
 
+use buildstructor::buildstructor;
+use futures::stream::{self, Stream, StreamExt};
+use hashbrown::HashSet;
 use indicatif::{ProgressBar, ProgressStyle};
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use pnet::ipnetwork::Ipv4Network;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Weak};
 use std::time::Duration;
 use tokio::net::UdpSocket;
-use tokio::time as tktime;
-use tracing::{debug, error, info, instrument, warn};
+use tokio::sync::{broadcast, Mutex as AsyncMutex};
+use tokio::task::JoinHandle;
+use tokio::time::{self as tktime, Instant};
+use tracing::{debug, error, info, instrument};
 
 pub const PORT: u16 = 38899;
 pub const DEFAULT_WAIT_TIME: f64 = 5.0;
-pub const REGISTER_MESSAGE: &str = r#"{"method":"registration","params":{"phoneMac":"AAAAAAAAAAAA","register":false,"phoneIp":"1.2.3.4","id":"1"}}"#;
+pub const DEFAULT_BROADCASTS: u32 = 3;
+pub const DEFAULT_BROADCAST_INTERVAL: f64 = 1.0;
+/// Hosts probed at once by [BroadcastProtocol::sweep].
+pub const DEFAULT_SWEEP_CONCURRENCY: usize = 64;
+/// How long [BroadcastProtocol::sweep] waits for each host.
+pub const SWEEP_HOST_TIMEOUT: f64 = 1.5;
+/// Delay between two background discovery rounds.
+pub const DEFAULT_RESCAN_INTERVAL: f64 = 30.0;
+/// How long a bulb may stay silent before it is reported as gone.
+pub const DEFAULT_GRACE_PERIOD: f64 = 120.0;
+/// Bulbs queried for their details at once, while discovering or by
+/// [BroadcastProtocol::verify_cached].
+const PROBE_CONCURRENCY: usize = 16;
+/// Time in seconds to wait for each reply when querying bulb details.
+const PROBE_TIMEOUT: f64 = 2.0;
+/// Events buffered for slow [BroadcastProtocol::subscribe] receivers.
+const EVENT_CAPACITY: usize = 64;
+
+/// The `registration` request broadcast to find bulbs.
+///
+/// `register` is false, so the bulbs only answer and do not start pushing updates.
+pub fn register_message() -> Request<RegistrationParams> {
+    let mut params =
+        RegistrationParams::new("1.2.3.4".to_string(), false, "AAAAAAAAAAAA".to_string());
+    params.id = Some("1".to_string());
+    Request::new(Method::Registration, params)
+}
+
+/// How [BroadcastProtocol] looks for bulbs.
+#[derive(Debug, Clone)]
+pub struct DiscoveryConfig {
+    /// How long [BroadcastProtocol::discover] waits for answers.
+    pub timeout: Duration,
+    /// Number of registration broadcasts sent per discovery.
+    pub broadcasts: u32,
+    /// Delay between two broadcasts.
+    pub broadcast_interval: Duration,
+    pub bind_addr: Ipv4Addr,
+    /// Local port, ephemeral when 0.
+    pub bind_port: u16,
+    /// Name of the network interface to discover on, all of them when unset.
+    ///
+    /// The socket is bound to its IPv4 address instead of `bind_addr`.
+    pub interface: Option<String>,
+    /// Send registrations only to this address.
+    ///
+    /// When unset they go to the directed broadcast address of every local
+    /// IPv4 interface, or to 255.255.255.255 if there are none.
+    pub broadcast_addr: Option<SocketAddr>,
+    /// Port bulbs listen on, for broadcasts, sweeps and detail queries.
+    pub bulb_port: u16,
+    /// Delay between two rounds of [BroadcastProtocol::start_watching].
+    pub rescan_interval: Duration,
+    /// How long a bulb may miss rounds before it is removed from the registry.
+    pub grace_period: Duration,
+    /// Query every new bulb for its system and model config.
+    pub enrich: bool,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs_f64(DEFAULT_WAIT_TIME),
+            broadcasts: DEFAULT_BROADCASTS,
+            broadcast_interval: Duration::from_secs_f64(DEFAULT_BROADCAST_INTERVAL),
+            bind_addr: Ipv4Addr::UNSPECIFIED,
+            bind_port: 0,
+            interface: None,
+            broadcast_addr: None,
+            bulb_port: PORT,
+            rescan_interval: Duration::from_secs_f64(DEFAULT_RESCAN_INTERVAL),
+            grace_period: Duration::from_secs_f64(DEFAULT_GRACE_PERIOD),
+            enrich: false,
+        }
+    }
+}
+
+#[buildstructor]
+impl DiscoveryConfig {
+    #[builder]
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        timeout: Option<Duration>,
+        broadcasts: Option<u32>,
+        broadcast_interval: Option<Duration>,
+        bind_addr: Option<Ipv4Addr>,
+        bind_port: Option<u16>,
+        interface: Option<String>,
+        broadcast_addr: Option<SocketAddr>,
+        bulb_port: Option<u16>,
+        rescan_interval: Option<Duration>,
+        grace_period: Option<Duration>,
+        enrich: Option<bool>,
+    ) -> Self {
+        let default = Self::default();
+        Self {
+            timeout: timeout.unwrap_or(default.timeout),
+            broadcasts: broadcasts.unwrap_or(default.broadcasts),
+            broadcast_interval: broadcast_interval.unwrap_or(default.broadcast_interval),
+            bind_addr: bind_addr.unwrap_or(default.bind_addr),
+            bind_port: bind_port.unwrap_or(default.bind_port),
+            interface,
+            broadcast_addr,
+            bulb_port: bulb_port.unwrap_or(default.bulb_port),
+            rescan_interval: rescan_interval.unwrap_or(default.rescan_interval),
+            grace_period: grace_period.unwrap_or(default.grace_period),
+            enrich: enrich.unwrap_or(default.enrich),
+        }
+    }
+}
+
+fn fill_system(bulb: &mut DiscoveredBulb, config: SystemConfig) {
+    bulb.module_name = Some(config.module_name);
+    bulb.fw_version = config.fw_version;
+    bulb.home_id = config.home_id;
+    bulb.room_id = config.room_id;
+    bulb.group_id = config.group_id;
+    bulb.type_id = config.type_id;
+}
+
+/// Copy what a probe learned into `bulb`.
+fn fill_details(bulb: &mut DiscoveredBulb, info: BulbInfo) {
+    fill_system(bulb, info.system_config);
+    bulb.class = Some(info.bulb_type);
+    bulb.model_config = info.model_config;
+    bulb.white_range = info.white_range;
+    bulb.ext_white_range = info.ext_white_range;
+}
+
+struct DiscoverState {
+    sent: u32,
+    next_send: Instant,
+    seen: HashSet<String>,
+}
 
 pub struct BroadcastProtocol {
-    pub reg: BulbRegistry,
-    broadcast_addr: SocketAddr,
+    pub reg: Arc<BulbRegistry>,
+    config: DiscoveryConfig,
     transport: UdpSocket,
     local_addrs: Vec<String>,
+    nets: Vec<InterfaceNet>,
+    events: broadcast::Sender<DiscoveryEvent>,
+    watcher: Mutex<Option<JoinHandle<()>>>,
+    probe_transport: OnceCell<WizProtocol>,
+    locating: AsyncMutex<()>,
+}
+
+/// Rediscover every [DiscoveryConfig::rescan_interval] and prune silent bulbs
+/// until the protocol is dropped or [BroadcastProtocol::stop_watching] is called.
+async fn watch_loop(this: Weak<BroadcastProtocol>) {
+    loop {
+        let Some(proto) = this.upgrade() else {
+            return;
+        };
+        {
+            let stream = proto.discover_stream(proto.config.timeout);
+            tokio::pin!(stream);
+            while let Some(r) = stream.next().await {
+                if let Err(e) = r {
+                    error!("Error encountered {e}");
+                }
+            }
+        }
+        for bulb in proto.reg.prune(proto.config.grace_period) {
+            info!(
+                "Bulb {} at {} disappeared",
+                bulb.mac_address, bulb.ip_address
+            );
+            let _ = proto
+                .events
+                .send(DiscoveryEvent::Disappeared((*bulb).clone()));
+        }
+        let interval = proto.config.rescan_interval;
+        drop(proto);
+        tktime::sleep(interval).await;
+    }
 }
 
 impl BroadcastProtocol {
     #[instrument]
     pub fn new(addr: Option<&str>) -> Result<Self> {
-        let broadcast_addr = addr
-            .and_then(|x| x.parse::<SocketAddr>().ok())
-            .unwrap_or(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::BROADCAST, PORT)));
-        let transport = create_udp_broadcast(38899)?;
+        let mut config = DiscoveryConfig::default();
+        if let Some(addr) = addr.and_then(|x| x.parse::<SocketAddr>().ok()) {
+            config.broadcast_addr = Some(addr);
+        }
+        Self::with_config(config)
+    }
+    #[instrument]
+    pub fn with_config(config: DiscoveryConfig) -> Result<Self> {
+        Self::with_registry(config, Arc::new(BulbRegistry::default()))
+    }
+    /// Discover into an existing registry, e.g. one from [BulbRegistry::load].
+    #[instrument(skip(reg))]
+    pub fn with_registry(config: DiscoveryConfig, reg: Arc<BulbRegistry>) -> Result<Self> {
+        let bind_addr = match &config.interface {
+            Some(name) => {
+                get_interface_addr(name).ok_or_else(|| WizError::NoInterface(name.clone()))?
+            }
+            None => config.bind_addr,
+        };
+        let transport =
+            create_udp_bound(SocketAddrV4::new(bind_addr, config.bind_port), true, true)?;
         debug!("Created the udp socket");
-        let reg = BulbRegistry::default();
+        let nets = get_broadcast_nets()
+            .into_iter()
+            .filter(|n| match &config.interface {
+                Some(name) => *name == n.name,
+                None => true,
+            })
+            .collect();
         Ok(Self {
             reg,
-            broadcast_addr,
+            config,
             transport,
             local_addrs: get_local_adddrs(),
+            nets,
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            watcher: Mutex::new(None),
+            probe_transport: OnceCell::new(),
+            locating: AsyncMutex::new(()),
         })
     }
+    pub fn config(&self) -> &DiscoveryConfig {
+        &self.config
+    }
+    /// Receive the [DiscoveryEvent]s of every later discovery.
+    pub fn subscribe(&self) -> broadcast::Receiver<DiscoveryEvent> {
+        self.events.subscribe()
+    }
+    /// Keep [BroadcastProtocol::reg] up to date in a background task.
+    ///
+    /// Discovery is repeated every [DiscoveryConfig::rescan_interval] and bulbs
+    /// silent for longer than [DiscoveryConfig::grace_period] are removed. Do not
+    /// run [BroadcastProtocol::discover] meanwhile, both would read the same socket.
+    pub fn start_watching(self: &Arc<Self>) {
+        let mut watcher = self.watcher.lock();
+        if watcher.is_none() {
+            *watcher = Some(tokio::spawn(watch_loop(Arc::downgrade(self))));
+        }
+    }
+    pub fn stop_watching(&self) {
+        if let Some(handle) = self.watcher.lock().take() {
+            handle.abort();
+        }
+    }
+    pub fn is_watching(&self) -> bool {
+        self.watcher.lock().is_some()
+    }
+    /// Record that `bulb` answered and publish the resulting event.
+    fn seen(&self, mut bulb: DiscoveredBulb) -> DiscoveredBulb {
+        bulb.interface = self.interface_for(&bulb.ip_address);
+        if let Some(event) = self.reg.seen(bulb.clone()) {
+            let _ = self.events.send(event);
+        }
+        bulb
+    }
     #[instrument(skip(self, buf))]
-    pub async fn recv_from(&self, mut buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
-        let (n, addr) = self.transport.recv_from(buf).await?;
-        buf = buf.split_at_mut(n).0;
-        Ok((n, addr))
+    pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        Ok(self.transport.recv_from(buf).await?)
     }
     #[instrument(skip(self))]
     pub async fn recv_foreign(&self) -> Result<(Vec<u8>, SocketAddr)> {
@@ -52,7 +297,7 @@ impl BroadcastProtocol {
                 let ad = a.ip().to_string();
                 if !self.local_addrs.contains(&ad) {
                     info!("Received {} bytes from {}", n, ad);
-                    return Ok((buf.to_vec(), addr));
+                    return Ok((buf[..n].to_vec(), addr));
                 }
             }
         }
@@ -64,11 +309,308 @@ impl BroadcastProtocol {
         msg.ip = Some(addr);
         Ok(msg)
     }
+    /// Addresses registrations are sent to.
+    pub fn broadcast_addrs(&self) -> Vec<SocketAddr> {
+        if let Some(addr) = self.config.broadcast_addr {
+            return vec![addr];
+        }
+        let mut addrs: Vec<SocketAddr> = self
+            .nets
+            .iter()
+            .map(|n| SocketAddr::V4(SocketAddrV4::new(n.broadcast(), self.config.bulb_port)))
+            .collect();
+        addrs.sort();
+        addrs.dedup();
+        if addrs.is_empty() {
+            addrs.push(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::BROADCAST,
+                self.config.bulb_port,
+            )));
+        }
+        addrs
+    }
+    async fn send_registration(&self) -> Result<()> {
+        let msg = serde_json::to_vec(&register_message())?;
+        for addr in self.broadcast_addrs() {
+            debug!("Sending registration to {}", addr);
+            self.transport.send_to(&msg, addr).await?;
+        }
+        Ok(())
+    }
+    /// Name of the local interface whose subnet contains `ip`.
+    fn interface_for(&self, ip: &str) -> Option<String> {
+        let ip = ip.parse().ok()?;
+        self.nets
+            .iter()
+            .find(|n| n.contains(ip))
+            .map(|n| n.name.clone())
+    }
+    /// Broadcast registrations and yield each bulb as soon as it answers.
+    ///
+    /// Every bulb is yielded once, registered in [BroadcastProtocol::reg] and
+    /// announced to [BroadcastProtocol::subscribe] receivers if it is new or moved.
+    /// With [DiscoveryConfig::enrich] each bulb is queried for its details first,
+    /// and yielded without them if the queries are still running at `timeout`.
+    /// The stream ends when `timeout` elapses; malformed or failed replies are
+    /// yielded as errors without ending it.
+    pub fn discover_stream(
+        &self,
+        timeout: Duration,
+    ) -> impl Stream<Item = Result<DiscoveredBulb>> + '_ {
+        let deadline = Instant::now() + timeout;
+        self.replies(timeout)
+            .map(move |r| async move {
+                let mut bulb = r?;
+                if self.config.enrich
+                    && tktime::timeout_at(deadline, self.enrich(&mut bulb, None))
+                        .await
+                        .is_err()
+                {
+                    debug!("Gave up querying details of {}", bulb.ip_address);
+                }
+                let bulb = self.seen(bulb);
+                info!(
+                    "Discovered bulb with IP {} and MAC: {}",
+                    bulb.ip_address, bulb.mac_address
+                );
+                Ok(bulb)
+            })
+            .buffer_unordered(PROBE_CONCURRENCY)
+    }
+    /// Bulbs answering registration broadcasts until `timeout`, each once.
+    fn replies(&self, timeout: Duration) -> impl Stream<Item = Result<DiscoveredBulb>> + '_ {
+        let start = Instant::now();
+        let deadline = start + timeout;
+        let state = DiscoverState {
+            sent: 0,
+            next_send: start,
+            seen: HashSet::new(),
+        };
+        stream::unfold(state, move |mut st| async move {
+            loop {
+                let pending_sends = st.sent < self.config.broadcasts.max(1);
+                if pending_sends && Instant::now() >= st.next_send {
+                    st.sent += 1;
+                    st.next_send += self.config.broadcast_interval;
+                    if let Err(e) = self.send_registration().await {
+                        return Some((Err(e), st));
+                    }
+                    continue;
+                }
+                let wake = if pending_sends {
+                    st.next_send.min(deadline)
+                } else {
+                    deadline
+                };
+                let msg = match tktime::timeout_at(wake, self.recv_msg()).await {
+                    Err(_) if Instant::now() >= deadline => return None,
+                    Err(_) => continue,
+                    Ok(Err(e)) => return Some((Err(e), st)),
+                    Ok(Ok(msg)) => msg,
+                };
+                match DiscoveredBulb::try_from(msg) {
+                    Ok(bulb) if !st.seen.insert(bulb.mac_address.clone()) => continue,
+                    Ok(bulb) => return Some((Ok(bulb), st)),
+                    Err(e) => return Some((Err(e), st)),
+                }
+            }
+        })
+    }
+    /// Transport for querying bulb details, created on first use.
+    fn probe_transport(&self) -> Result<&WizProtocol> {
+        self.probe_transport.get_or_try_init(|| {
+            Ok(WizProtocol::new()?
+                .with_port(self.config.bulb_port)
+                .with_timeout(Duration::from_secs_f64(PROBE_TIMEOUT)))
+        })
+    }
+    /// Query the module, firmware and class of `bulb` unless already known.
+    ///
+    /// A `system_config` the bulb already sent is not queried again. Failures
+    /// are logged and leave `bulb` as it was.
+    async fn enrich(&self, bulb: &mut DiscoveredBulb, system_config: Option<SystemConfig>) {
+        if let Some(known) = self.reg.get(&bulb.mac_address) {
+            if known.class.is_some() && known.ip_address == bulb.ip_address {
+                return;
+            }
+        }
+        let res = match (self.probe_transport(), bulb.ip_address.parse()) {
+            (Ok(transport), Ok(ip)) => match system_config {
+                Some(config) => probe_with(transport, ip, config).await,
+                None => probe(transport, ip).await,
+            },
+            (Err(e), _) => Err(e),
+            (_, Err(e)) => Err(WizError::from(e)),
+        };
+        match res {
+            Ok(info) => fill_details(bulb, info),
+            Err(e) => debug!("Cannot query details of {}: {e}", bulb.ip_address),
+        }
+    }
+    /// Find where the bulb with `mac` answers, given that it stopped answering
+    /// at `stale_ip` if that is set.
+    ///
+    /// A registry entry with another IP is returned right away, otherwise
+    /// registrations are broadcast until the bulb answers or
+    /// [DiscoveryConfig::timeout] elapses, or until the registry learns the new
+    /// IP some other way, such as from the watcher. A new IP is announced as
+    /// [DiscoveryEvent::IpChanged]. Concurrent calls run one at a time so that
+    /// later ones can reuse what earlier ones found.
+    #[instrument(skip(self))]
+    pub async fn locate(&self, mac: &str, stale_ip: Option<IpAddr>) -> Result<Arc<DiscoveredBulb>> {
+        let stale_ip = stale_ip.map(|ip| ip.to_string());
+        let fresh = |b: &DiscoveredBulb| stale_ip.as_deref() != Some(b.ip_address.as_str());
+        let moved = || self.reg.get(mac).filter(|b| fresh(b));
+        if let Some(bulb) = moved() {
+            return Ok(bulb);
+        }
+        let _guard = self.locating.lock().await;
+        // The watcher shares our socket and may take the reply, so also
+        // follow the registry for the bulb showing up at another IP.
+        let mut updates = self.reg.subscribe();
+        if let Some(bulb) = moved() {
+            return Ok(bulb);
+        }
+        let stream = self.discover_stream(self.config.timeout);
+        tokio::pin!(stream);
+        let mut following = true;
+        loop {
+            tokio::select! {
+                r = stream.next() => match r {
+                    Some(Ok(bulb)) if bulb.mac_address == mac => {
+                        return Ok(self.reg.get(mac).unwrap_or_else(|| Arc::new(bulb)));
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => debug!("Error encountered {e}"),
+                    None => break,
+                },
+                ev = updates.recv(), if following => match ev {
+                    Ok(RegistryEvent::Added(bulb) | RegistryEvent::Updated { new: bulb, .. })
+                        if bulb.mac_address == mac && fresh(&bulb) =>
+                    {
+                        return Ok(bulb);
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        if let Some(bulb) = moved() {
+                            return Ok(bulb);
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => following = false,
+                },
+            }
+        }
+        if let Some(bulb) = moved() {
+            return Ok(bulb);
+        }
+        Err(WizError::BulbNotFound(mac.to_string()))
+    }
+    /// Probe every registered bulb by unicast in a background task.
+    ///
+    /// Meant for a registry loaded from a cache: answering bulbs get their
+    /// last-seen time, module, firmware and class refreshed, and an entry whose
+    /// IP now belongs to another bulb is replaced by that bulb. Silent bulbs are
+    /// left for [BroadcastProtocol::start_watching] to prune.
+    pub fn verify_cached(self: &Arc<Self>) -> JoinHandle<()> {
+        let this = self.clone();
+        tokio::spawn(async move {
+            let transport = match this.probe_transport() {
+                Ok(t) => t,
+                Err(e) => return error!("Cannot verify cached bulbs: {e}"),
+            };
+            let mut results = stream::iter(this.reg.bulbs())
+                .map(|cached| async move {
+                    let res = match cached.ip_address.parse() {
+                        Ok(ip) => probe(transport, ip).await,
+                        Err(e) => Err(WizError::from(e)),
+                    };
+                    (cached, res)
+                })
+                .buffer_unordered(PROBE_CONCURRENCY);
+            while let Some((cached, res)) = results.next().await {
+                match res {
+                    Ok(info) => {
+                        if info.system_config.mac != cached.mac_address {
+                            info!(
+                                "{} now belongs to {}, dropping {}",
+                                cached.ip_address, info.system_config.mac, cached.mac_address
+                            );
+                            this.reg.remove(&cached.mac_address);
+                        }
+                        let mut bulb = DiscoveredBulb::new(
+                            cached.ip_address.clone(),
+                            info.system_config.mac.clone(),
+                        );
+                        fill_details(&mut bulb, info);
+                        this.seen(bulb);
+                    }
+                    Err(e) => debug!(
+                        "Cached bulb {} at {} did not answer: {e}",
+                        cached.mac_address, cached.ip_address
+                    ),
+                }
+            }
+        })
+    }
+    /// Hosts of `network` worth probing: not this machine, and neither the
+    /// network nor the broadcast address unless the prefix is /31 or /32.
+    fn sweep_hosts(&self, network: Ipv4Network) -> impl Iterator<Item = Ipv4Addr> + '_ {
+        network.iter().filter(move |ip| {
+            let edge =
+                network.prefix() < 31 && (*ip == network.network() || *ip == network.broadcast());
+            !edge && !self.local_addrs.contains(&ip.to_string())
+        })
+    }
+    /// Ask every host of `network`, e.g. `10.20.0.0/22`, for its system config
+    /// by unicast and register each bulb that answers.
+    ///
+    /// For networks whose access points filter broadcast traffic. At most
+    /// `concurrency` hosts are probed at once.
+    #[instrument(skip(self))]
+    pub async fn sweep(&self, network: &str, concurrency: usize) -> Result<Vec<DiscoveredBulb>> {
+        let network: Ipv4Network = network.parse()?;
+        let transport = WizProtocol::new()?
+            .with_port(self.config.bulb_port)
+            .with_timeout(Duration::from_secs_f64(SWEEP_HOST_TIMEOUT));
+        let transport = &transport;
+        let mut responses = stream::iter(self.sweep_hosts(network))
+            .map(|ip| async move {
+                let res: Result<SystemConfig> = transport
+                    .call(
+                        IpAddr::V4(ip),
+                        Method::GetSystemConfig,
+                        serde_json::json!({}),
+                    )
+                    .await;
+                (ip, res)
+            })
+            .buffer_unordered(concurrency.max(1));
+        let mut found: Vec<DiscoveredBulb> = Vec::new();
+        while let Some((ip, res)) = responses.next().await {
+            match res {
+                Ok(config) => {
+                    if found.iter().any(|b| b.mac_address == config.mac) {
+                        continue;
+                    }
+                    let mut bulb = DiscoveredBulb::new(ip.to_string(), config.mac.clone());
+                    fill_system(&mut bulb, config.clone());
+                    if self.config.enrich {
+                        self.enrich(&mut bulb, Some(config)).await;
+                    }
+                    let bulb = self.seen(bulb);
+                    info!(
+                        "Found bulb with IP {} and MAC: {}",
+                        bulb.ip_address, bulb.mac_address
+                    );
+                    found.push(bulb);
+                }
+                Err(e) => debug!("No bulb at {}: {}", ip, e),
+            }
+        }
+        Ok(found)
+    }
     #[instrument(skip(self))]
     pub async fn discover(&self) -> Result<()> {
-        self.transport
-            .send_to(REGISTER_MESSAGE.as_bytes(), self.broadcast_addr)
-            .await?;
         let sp = ProgressBar::new_spinner();
         sp.enable_steady_tick(Duration::from_millis(120));
         sp.set_style(
@@ -86,26 +628,159 @@ impl BroadcastProtocol {
                 ]),
         );
         sp.set_message("Discovering...");
-        let r: Result<Result<()>> =
-            tktime::timeout(Duration::from_secs_f64(DEFAULT_WAIT_TIME), async {
-                loop {
-                    let resp = self.recv_msg().await?;
-                    let to_reg: DiscoveredBulb = resp.try_into()?;
-                    info!(
-                        "Discovered bulb with IP {} and MAC: {}",
-                        to_reg.ip_address, to_reg.mac_address
-                    );
-                    self.reg.register(to_reg);
+        let mut found = 0;
+        let stream = self.discover_stream(self.config.timeout);
+        tokio::pin!(stream);
+        while let Some(r) = stream.next().await {
+            match r {
+                Ok(_) => {
+                    found += 1;
+                    sp.set_message(format!("Discovering... {found} found"));
                 }
-            })
-            .await
-            .map_err(WizError::TimeOut)?;
-        match r {
-            Ok(Err(e)) => error!("Error encountered {e}"),
-            Err(e) => warn!("Timeout {e}"),
-            _ => {}
-        }
-        sp.finish_with_message(format!("Discovered {} bulbs", self.reg.bulbs().len()));
+                Err(e) => error!("Error encountered {e}"),
+            }
+        }
+        sp.finish_with_message(format!("Discovered {found} bulbs"));
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{method_not_found, ok, FakeBulb};
+    use serde_json::json;
+
+    fn net(name: &str, cidr: &str) -> InterfaceNet {
+        InterfaceNet {
+            name: name.to_string(),
+            network: cidr.parse().unwrap(),
+        }
+    }
+
+    fn with_nets(config: DiscoveryConfig, nets: Vec<InterfaceNet>) -> BroadcastProtocol {
+        let mut proto = BroadcastProtocol::with_config(config).unwrap();
+        proto.nets = nets;
+        proto
+    }
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn broadcasts_once_per_subnet() {
+        let nets = vec![
+            net("eth0", "192.168.1.10/24"),
+            net("docker0", "172.17.0.1/16"),
+            net("eth1", "192.168.1.11/24"),
+            net("vlan20", "10.20.0.5/22"),
+        ];
+        let proto = with_nets(DiscoveryConfig::default(), nets.clone());
+        assert_eq!(
+            proto.broadcast_addrs(),
+            addrs(&[
+                "10.20.3.255:38899",
+                "172.17.255.255:38899",
+                "192.168.1.255:38899",
+            ])
+        );
+        let proto = with_nets(DiscoveryConfig::default(), Vec::new());
+        assert_eq!(proto.broadcast_addrs(), addrs(&["255.255.255.255:38899"]));
+        let config = DiscoveryConfig::builder()
+            .broadcast_addr(addrs(&["192.168.1.255:38899"])[0])
+            .build();
+        let proto = with_nets(config, nets);
+        assert_eq!(proto.broadcast_addrs(), addrs(&["192.168.1.255:38899"]));
+    }
+
+    #[tokio::test]
+    async fn tags_bulbs_with_their_interface() {
+        let nets = vec![net("eth0", "192.168.1.10/24"), net("iot", "10.20.0.5/22")];
+        let proto = with_nets(DiscoveryConfig::default(), nets);
+        let cases = [
+            ("192.168.1.42", Some("eth0")),
+            ("10.20.3.7", Some("iot")),
+            ("10.20.4.7", None),
+            ("fe80::1", None),
+            ("not an ip", None),
+        ];
+        for (ip, expected) in cases {
+            assert_eq!(proto.interface_for(ip).as_deref(), expected, "{ip}");
+        }
+    }
+
+    #[tokio::test]
+    async fn sweep_skips_edges_and_local_addresses() {
+        let mut proto = with_nets(DiscoveryConfig::default(), Vec::new());
+        proto.local_addrs = vec!["10.0.0.2".to_string()];
+        let hosts = |cidr: &str| -> Vec<String> {
+            proto
+                .sweep_hosts(cidr.parse().unwrap())
+                .map(|ip| ip.to_string())
+                .collect()
+        };
+        assert_eq!(
+            hosts("10.0.0.0/29"),
+            ["10.0.0.1", "10.0.0.3", "10.0.0.4", "10.0.0.5", "10.0.0.6"]
+        );
+        assert_eq!(hosts("10.0.0.4/31"), ["10.0.0.4", "10.0.0.5"]);
+        assert_eq!(hosts("10.0.0.9/32"), ["10.0.0.9"]);
+        assert!(hosts("10.0.0.2/32").is_empty());
+    }
+
+    #[tokio::test]
+    async fn sweep_reuses_the_system_config() {
+        let bulb = FakeBulb::spawn("127.0.0.1:0", |req| match req["method"].as_str()? {
+            "getSystemConfig" => ok(json!({
+                "mac": "a8bb50aabbcc",
+                "moduleName": "ESP01_SHRGB1C_31",
+                "fwVersion": "1.25.0"
+            })),
+            "getModelConfig" => ok(json!({"cctRange": [2200, 2700, 6500, 6500]})),
+            _ => method_not_found(),
+        })
+        .await;
+        let config = DiscoveryConfig::builder()
+            .bulb_port(bulb.port())
+            .enrich(true)
+            .build();
+        let mut proto = with_nets(config, Vec::new());
+        proto.local_addrs.clear();
+        let found = proto.sweep("127.0.0.1/32", 4).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(
+            found[0].to_string(),
+            "ESP01_SHRGB1C_31, RGB, fw 1.25.0, 2200–6500K"
+        );
+        assert!(proto.reg.is_registered("a8bb50aabbcc"));
+        assert_eq!(bulb.received("getSystemConfig"), 1);
+    }
+
+    #[tokio::test]
+    async fn discovery_ends_with_slow_enrichment() {
+        let bulb = FakeBulb::spawn("127.0.0.1:0", |req| match req["method"].as_str()? {
+            "registration" => ok(json!({"mac": "a8bb50aabbcc", "success": true})),
+            _ => None,
+        })
+        .await;
+        let config = DiscoveryConfig::builder()
+            .broadcast_addr(SocketAddr::new(bulb.ip(), bulb.port()))
+            .bulb_port(bulb.port())
+            .enrich(true)
+            .build();
+        let mut proto = with_nets(config, Vec::new());
+        proto.local_addrs.clear();
+        let start = Instant::now();
+        let found: Vec<_> = proto
+            .discover_stream(Duration::from_millis(500))
+            .collect()
+            .await;
+        assert!(start.elapsed() < Duration::from_secs_f64(PROBE_TIMEOUT));
+        assert_eq!(found.len(), 1);
+        let found = found[0].as_ref().unwrap();
+        assert_eq!(found.mac_address, "a8bb50aabbcc");
+        assert!(found.class.is_none());
+        assert!(bulb.received("getSystemConfig") > 0);
+    }
+}
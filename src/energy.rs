@@ -0,0 +1,109 @@
+use std::time::Instant;
+
+/// Accumulates energy from power samples taken over time.
+///
+/// Consecutive samples are joined by a straight line, so the energy between
+/// two samples is their mean power times the time between them.
+#[derive(Debug, Clone, Default)]
+pub struct EnergyMeter {
+    watt_hours: f64,
+    first: Option<Instant>,
+    last: Option<(Instant, f64)>,
+    samples: u64,
+}
+
+impl EnergyMeter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Add a reading of `watts` taken at `at`.
+    ///
+    /// Samples older than the previous one are ignored.
+    pub fn add_sample(&mut self, watts: f64, at: Instant) {
+        if let Some((prev_at, prev_watts)) = self.last {
+            let Some(elapsed) = at.checked_duration_since(prev_at) else {
+                return;
+            };
+            self.watt_hours += (prev_watts + watts) / 2.0 * elapsed.as_secs_f64() / 3600.0;
+        }
+        self.first.get_or_insert(at);
+        self.last = Some((at, watts));
+        self.samples += 1;
+    }
+    pub fn watt_hours(&self) -> f64 {
+        self.watt_hours
+    }
+    pub fn kilowatt_hours(&self) -> f64 {
+        self.watt_hours / 1000.0
+    }
+    /// Most recent reading in watts.
+    pub fn last_watts(&self) -> Option<f64> {
+        self.last.map(|(_, w)| w)
+    }
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+    /// Mean power between the first and the last sample.
+    pub fn average_watts(&self) -> Option<f64> {
+        let span = self.last?.0.duration_since(self.first?).as_secs_f64();
+        if span == 0.0 {
+            return None;
+        }
+        Some(self.watt_hours * 3600.0 / span)
+    }
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::close;
+    use std::time::Duration;
+
+    #[test]
+    fn trapezoidal_accumulation() {
+        let t0 = Instant::now();
+        let mut meter = EnergyMeter::new();
+        meter.add_sample(100.0, t0);
+        assert_eq!(meter.watt_hours(), 0.0);
+        meter.add_sample(100.0, t0 + Duration::from_secs(1800));
+        assert!(close(meter.watt_hours(), 50.0, 1e-9));
+        // Ramp from 100 W to 300 W over an hour: mean 200 W.
+        meter.add_sample(300.0, t0 + Duration::from_secs(5400));
+        assert!(close(meter.watt_hours(), 250.0, 1e-9));
+        assert!(close(meter.kilowatt_hours(), 0.25, 1e-9));
+        assert_eq!(meter.last_watts(), Some(300.0));
+        assert_eq!(meter.samples(), 3);
+    }
+
+    #[test]
+    fn ignores_out_of_order_samples() {
+        let t0 = Instant::now();
+        let mut meter = EnergyMeter::new();
+        meter.add_sample(60.0, t0 + Duration::from_secs(3600));
+        meter.add_sample(1000.0, t0);
+        assert_eq!(meter.watt_hours(), 0.0);
+        assert_eq!(meter.last_watts(), Some(60.0));
+        assert_eq!(meter.samples(), 1);
+        meter.add_sample(60.0, t0 + Duration::from_secs(7200));
+        assert!(close(meter.watt_hours(), 60.0, 1e-9));
+    }
+
+    #[test]
+    fn average_watts() {
+        let t0 = Instant::now();
+        let mut meter = EnergyMeter::new();
+        assert_eq!(meter.average_watts(), None);
+        meter.add_sample(10.0, t0);
+        assert_eq!(meter.average_watts(), None);
+        meter.add_sample(30.0, t0 + Duration::from_secs(60));
+        meter.add_sample(30.0, t0 + Duration::from_secs(120));
+        // 20 W for the first minute, 30 W for the second.
+        assert!(close(meter.average_watts().unwrap(), 25.0, 1e-9));
+        meter.reset();
+        assert_eq!(meter.samples(), 0);
+        assert_eq!(meter.average_watts(), None);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::messages::{BulbError, Method};
 use crate::models::RegistrationMessage;
 use socket2::SockAddr;
 use std::error::Error;
@@ -34,9 +35,47 @@ pub enum WizError {
     #[error("No maximum value")]
     NoMaximum,
     #[error("{0:?} is not an IPv4 address")]
-    IP6(SockAddr),
+    IP6(Box<SockAddr>),
     #[error("Address parse error: {0}")]
     AddrParseError(#[from] std::net::AddrParseError),
+    #[error("Invalid network: {0}")]
+    NetworkParseError(#[from] pnet::ipnetwork::IpNetworkError),
+    #[error("No response from {0} after {1} attempts")]
+    NoResponse(std::net::SocketAddr, u32),
+    #[error("Bulb returned an error: {0}")]
+    BulbErr(BulbError),
+    #[error("Response to {0} has neither a result nor an error")]
+    EmptyResponse(Method),
+    #[error("Bulb reply has no {0}")]
+    MissingField(&'static str),
+    #[error("Bulb did not accept {0}")]
+    CommandFailed(Method),
+    #[error("{0} is not supported by {1}")]
+    Unsupported(&'static str, String),
+    #[error("{0} must be between {1} and {2}, got {3}")]
+    OutOfRange(&'static str, i64, i64, i64),
+    #[error("{0:?} is not a [min, max] kelvin range")]
+    InvalidRange(Vec<u32>),
+    #[error("Color temperature {0}K is outside of {1}K-{2}K")]
+    KelvinOutOfRange(u32, f64, f64),
+    #[error("Unknown scene id {0}")]
+    UnknownScene(u32),
+    #[error("Scene {0} is not supported by {1}")]
+    SceneUnsupported(String, String),
+    #[error("{0} and {1} cannot be set together")]
+    ConflictingModes(&'static str, &'static str),
+    #[error("No bulb matching {0}")]
+    BulbNotFound(String),
+    #[error("{0} matches more than one bulb: {}", .1.join(", "))]
+    AmbiguousTarget(String, Vec<String>),
+    #[error("Bulb {0} did not come back within {1:?}")]
+    NotBack(String, std::time::Duration),
+    #[error("Bulb {0} still answers {2:?} after accepting {1}")]
+    NotRestarted(String, Method, std::time::Duration),
+    #[error("Factory reset was confirmed for {0}, not for {1}")]
+    ResetNotConfirmed(String, String),
+    #[error("No network interface {0} with an IPv4 address")]
+    NoInterface(String),
 }
 
 impl From<Box<dyn std::error::Error + Send + Sync + 'static>> for WizError {
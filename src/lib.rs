@@ -1,14 +1,19 @@
 #![allow(dead_code)]
-mod bulb;
-mod bulblibrary;
-mod cli;
+pub mod bulb;
+pub mod bulblibrary;
+pub mod cli;
 pub mod discovery;
+pub mod energy;
 mod errors;
-mod models;
-mod protocol;
-mod push_manager;
-mod rgbcw;
-mod scenes;
+pub mod messages;
+pub mod models;
+pub mod pilot;
+pub mod protocol;
+pub mod push_manager;
+pub mod rgbcw;
+pub mod scenes;
+#[cfg(test)]
+mod testing;
 mod utils;
 
 pub use errors::{Result, WizError};
@@ -21,7 +26,7 @@ pub use errors::{Result, WizError};
 /// ```rust
 /// use wizlight_rs::map;
 ///
-/// let goodbye = map! {
+/// let goodbye: hashbrown::HashMap<_, _> = map! {
 ///     "en" => "Goodbye",
 ///     "de" => "Auf Wiedersehen",
 ///     "fr" => "Au revoir",
@@ -45,7 +50,7 @@ macro_rules! map {
 /// ```rust
 /// use wizlight_rs::lazy_map;
 ///
-/// let goodbye = lazy_map! {
+/// let goodbye: once_cell::unsync::Lazy<hashbrown::HashMap<_, _>> = lazy_map! {
 ///     "en" => "Goodbye",
 ///     "de" => "Auf Wiedersehen",
 ///     "fr" => "Au revoir",
@@ -69,7 +74,7 @@ macro_rules! lazy_map {
 /// ```rust
 /// use wizlight_rs::lazy_sync_map;
 ///
-/// let goodbye = lazy_sync_map! {
+/// let goodbye: once_cell::sync::Lazy<hashbrown::HashMap<_, _>> = lazy_sync_map! {
 ///     "en" => "Goodbye",
 ///     "de" => "Auf Wiedersehen",
 ///     "fr" => "Au revoir",
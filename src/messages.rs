@@ -0,0 +1,478 @@
+use crate::{Result, WizError};
+
+use buildstructor::buildstructor;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// Methods understood by WiZ devices.
+///
+/// Anything not known to this crate is kept verbatim in [Method::Other].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Method {
+    GetPilot,
+    SetPilot,
+    SetState,
+    GetSystemConfig,
+    GetModelConfig,
+    GetUserConfig,
+    SetUserConfig,
+    Registration,
+    SyncPilot,
+    FirstBeat,
+    Reboot,
+    Reset,
+    GetPower,
+    Other(String),
+}
+
+impl Method {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::GetPilot => "getPilot",
+            Method::SetPilot => "setPilot",
+            Method::SetState => "setState",
+            Method::GetSystemConfig => "getSystemConfig",
+            Method::GetModelConfig => "getModelConfig",
+            Method::GetUserConfig => "getUserConfig",
+            Method::SetUserConfig => "setUserConfig",
+            Method::Registration => "registration",
+            Method::SyncPilot => "syncPilot",
+            Method::FirstBeat => "firstBeat",
+            Method::Reboot => "reboot",
+            Method::Reset => "reset",
+            Method::GetPower => "getPower",
+            Method::Other(m) => m,
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = std::convert::Infallible;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s {
+            "getPilot" => Method::GetPilot,
+            "setPilot" => Method::SetPilot,
+            "setState" => Method::SetState,
+            "getSystemConfig" => Method::GetSystemConfig,
+            "getModelConfig" => Method::GetModelConfig,
+            "getUserConfig" => Method::GetUserConfig,
+            "setUserConfig" => Method::SetUserConfig,
+            "registration" => Method::Registration,
+            "syncPilot" => Method::SyncPilot,
+            "firstBeat" => Method::FirstBeat,
+            "reboot" => Method::Reboot,
+            "reset" => Method::Reset,
+            "getPower" => Method::GetPower,
+            other => Method::Other(other.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Method {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Method {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Ok(s.parse().unwrap_or_else(|e| match e {}))
+    }
+}
+
+/// Parameters of a `registration` request.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RegistrationParams {
+    #[serde(rename = "phoneMac")]
+    pub phone_mac: String,
+    pub register: bool,
+    #[serde(rename = "phoneIp")]
+    pub phone_ip: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub id: Option<String>,
+}
+
+impl RegistrationParams {
+    pub fn new(phone_ip: String, register: bool, phone_mac: String) -> Self {
+        Self {
+            phone_mac,
+            register,
+            phone_ip,
+            id: None,
+        }
+    }
+}
+
+/// Request envelope: `{"id":1,"method":"getPilot","params":{}}`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Request<P = serde_json::Value> {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub id: Option<u32>,
+    pub method: Method,
+    pub params: P,
+}
+
+impl<P> Request<P> {
+    pub fn new(method: Method, params: P) -> Self {
+        Self {
+            id: None,
+            method,
+            params,
+        }
+    }
+    pub fn with_id(mut self, id: u32) -> Self {
+        self.id = Some(id);
+        self
+    }
+}
+
+impl Request<serde_json::Value> {
+    /// Request without parameters, sent as `"params":{}`.
+    pub fn empty(method: Method) -> Self {
+        Self::new(method, serde_json::Value::Object(Default::default()))
+    }
+}
+
+/// Response envelope, carrying either a `result` or an `error`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Response<R = serde_json::Value> {
+    pub method: Method,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub id: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub env: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<R>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub error: Option<BulbError>,
+}
+
+impl<R> Response<R> {
+    /// Turn the envelope into the carried result, or the bulb's error.
+    pub fn into_result(self) -> Result<R> {
+        match (self.result, self.error) {
+            (_, Some(e)) => Err(WizError::BulbErr(e)),
+            (Some(r), None) => Ok(r),
+            (None, None) => Err(WizError::EmptyResponse(self.method)),
+        }
+    }
+}
+
+impl Response<serde_json::Value> {
+    /// Re-read an untyped response as a typed one.
+    pub fn typed<R: DeserializeOwned>(self) -> Result<Response<R>> {
+        let result = self.result.map(serde_json::from_value).transpose()?;
+        Ok(Response {
+            method: self.method,
+            id: self.id,
+            env: self.env,
+            result,
+            error: self.error,
+        })
+    }
+}
+
+/// Error object returned by the bulb, e.g. `{"code":-32601,"message":"Method not found"}`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BulbError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl fmt::Display for BulbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (code {})", self.message, self.code)
+    }
+}
+
+impl BulbError {
+    pub const METHOD_NOT_FOUND: i64 = -32601;
+
+    pub fn is_method_not_found(&self) -> bool {
+        self.code == Self::METHOD_NOT_FOUND
+    }
+}
+
+/// Result of a `setPilot`, `setState` or similar command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Success {
+    pub success: bool,
+}
+
+/// Result of `getPower`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Power {
+    /// Current draw in milliwatts.
+    pub power: u64,
+}
+
+impl Power {
+    pub fn watts(&self) -> f64 {
+        self.power as f64 / 1000.0
+    }
+}
+
+/// Result of `getUserConfig` and params of `setUserConfig`.
+///
+/// Only the fields that are set are sent, so build updates with
+/// [UserConfig::update_builder] to leave everything else untouched.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserConfig {
+    /// Fade-in duration in milliseconds.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub fade_in: Option<u32>,
+    /// Fade-out duration in milliseconds.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub fade_out: Option<u32>,
+    /// Power-on behavior after a power cut: restore the last state when true,
+    /// turn on at full brightness when false.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub po: Option<bool>,
+    /// Lowest brightness in percent the dimmer goes down to.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub min_dimming: Option<u8>,
+    /// `[min, max]` kelvin override.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub white_range: Option<Vec<u32>>,
+    /// `[min, max]` extended kelvin override.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub ext_range: Option<Vec<u32>>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[buildstructor]
+impl UserConfig {
+    #[builder]
+    pub fn update_new(
+        fade_in: Option<u32>,
+        fade_out: Option<u32>,
+        po: Option<bool>,
+        min_dimming: Option<u8>,
+        white_range: Option<(u32, u32)>,
+        ext_range: Option<(u32, u32)>,
+    ) -> Self {
+        Self {
+            fade_in,
+            fade_out,
+            po,
+            min_dimming,
+            white_range: white_range.map(|(min, max)| vec![min, max]),
+            ext_range: ext_range.map(|(min, max)| vec![min, max]),
+            extra: serde_json::Map::new(),
+        }
+    }
+    /// Check the values before they are sent.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(min) = self.min_dimming {
+            if min > 100 {
+                return Err(WizError::OutOfRange("minimum dimming", 0, 100, min as i64));
+            }
+        }
+        for range in [&self.white_range, &self.ext_range].into_iter().flatten() {
+            match range.as_slice() {
+                [min, max] if min < max => {}
+                _ => return Err(WizError::InvalidRange(range.clone())),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Result of `getSystemConfig`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SystemConfig {
+    pub mac: String,
+    pub module_name: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub fw_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub home_id: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub room_id: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub group_id: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub type_id: Option<i64>,
+    /// Reported by older firmware; newer ones use `cctRange` in [ModelConfig].
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub white_range: Option<Vec<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub ext_range: Option<Vec<f64>>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Result of `getModelConfig`, only answered by newer firmware.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModelConfig {
+    /// `[ext_min, min, max, ext_max]` in kelvin.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub cct_range: Option<Vec<f64>>,
+    /// White to color ratio.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub wcr: Option<i64>,
+    /// Number of white channels.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub nowc: Option<i64>,
+    /// Number of fan speeds, only reported by fans.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub fan_speed: Option<u8>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn request_wire_format() {
+        let req = Request::empty(Method::GetPilot);
+        assert_eq!(
+            serde_json::to_string(&req).unwrap(),
+            r#"{"method":"getPilot","params":{}}"#
+        );
+        let req = Request::new(Method::SetPilot, json!({"state": true})).with_id(7);
+        let s = serde_json::to_string(&req).unwrap();
+        assert_eq!(s, r#"{"id":7,"method":"setPilot","params":{"state":true}}"#);
+        assert_eq!(serde_json::from_str::<Request>(&s).unwrap(), req);
+    }
+
+    #[test]
+    fn registration_wire_format() {
+        let mut params =
+            RegistrationParams::new("1.2.3.4".to_string(), false, "AAAAAAAAAAAA".to_string());
+        params.id = Some("1".to_string());
+        let req = Request::new(Method::Registration, params);
+        let s = serde_json::to_string(&req).unwrap();
+        assert_eq!(
+            s,
+            r#"{"method":"registration","params":{"phoneMac":"AAAAAAAAAAAA","register":false,"phoneIp":"1.2.3.4","id":"1"}}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Request<RegistrationParams>>(&s).unwrap(),
+            req
+        );
+    }
+
+    #[test]
+    fn response_result() {
+        let raw = r#"{"method":"setPilot","env":"pro","result":{"success":true}}"#;
+        let resp: Response<Success> = serde_json::from_str(raw).unwrap();
+        assert_eq!(resp.method, Method::SetPilot);
+        assert_eq!(resp.env.as_deref(), Some("pro"));
+        assert_eq!(serde_json::to_string(&resp).unwrap(), raw);
+        assert!(resp.into_result().unwrap().success);
+    }
+
+    #[test]
+    fn response_error() {
+        let raw = r#"{"method":"getPower","env":"pro","error":{"code":-32601,"message":"Method not found"}}"#;
+        let resp: Response = serde_json::from_str(raw).unwrap();
+        assert_eq!(serde_json::to_string(&resp).unwrap(), raw);
+        match resp.into_result() {
+            Err(WizError::BulbErr(e)) => {
+                assert!(e.is_method_not_found());
+                assert_eq!(e.message, "Method not found");
+            }
+            other => panic!("expected a bulb error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn unknown_method_round_trips() {
+        let m: Method = serde_json::from_str(r#""setFanState""#).unwrap();
+        assert_eq!(m, Method::Other("setFanState".to_string()));
+        assert_eq!(serde_json::to_string(&m).unwrap(), r#""setFanState""#);
+        let m: Method = serde_json::from_str(r#""syncPilot""#).unwrap();
+        assert_eq!(m, Method::SyncPilot);
+    }
+
+    #[test]
+    fn user_config_round_trips() {
+        let raw = json!({
+            "fadeIn": 0,
+            "fadeOut": 0,
+            "fadeNight": false,
+            "dftDim": 100,
+            "pwmRange": [0, 100],
+            "whiteRange": [2200, 6500],
+            "extRange": [1800, 6500],
+            "po": false,
+            "minDimming": 10
+        });
+        let config: UserConfig = serde_json::from_value(raw.clone()).unwrap();
+        assert_eq!(config.fade_in, Some(0));
+        assert_eq!(config.fade_out, Some(0));
+        assert_eq!(config.po, Some(false));
+        assert_eq!(config.min_dimming, Some(10));
+        assert_eq!(config.white_range, Some(vec![2200, 6500]));
+        assert_eq!(config.ext_range, Some(vec![1800, 6500]));
+        assert_eq!(config.extra["dftDim"], 100);
+        assert_eq!(serde_json::to_value(&config).unwrap(), raw);
+        let update = UserConfig::update_builder()
+            .fade_in(500u32)
+            .po(true)
+            .build();
+        assert_eq!(
+            serde_json::to_value(&update).unwrap(),
+            json!({"fadeIn": 500, "po": true})
+        );
+    }
+
+    #[test]
+    fn user_config_validate() {
+        let cases = [
+            (UserConfig::update_builder().build(), None),
+            (
+                UserConfig::update_builder()
+                    .min_dimming(100u8)
+                    .white_range((2700u32, 6500u32))
+                    .ext_range((2200u32, 6500u32))
+                    .build(),
+                None,
+            ),
+            (
+                UserConfig::update_builder().min_dimming(101u8).build(),
+                Some("minimum dimming must be between 0 and 100, got 101"),
+            ),
+            (
+                UserConfig::update_builder()
+                    .white_range((6500u32, 2700u32))
+                    .build(),
+                Some("[6500, 2700] is not a [min, max] kelvin range"),
+            ),
+            (
+                UserConfig::update_builder()
+                    .ext_range((4000u32, 4000u32))
+                    .build(),
+                Some("[4000, 4000] is not a [min, max] kelvin range"),
+            ),
+            (
+                UserConfig {
+                    white_range: Some(vec![2200, 4000, 6500]),
+                    ..Default::default()
+                },
+                Some("[2200, 4000, 6500] is not a [min, max] kelvin range"),
+            ),
+        ];
+        for (config, expected) in cases {
+            let got = config.validate().err().map(|e| e.to_string());
+            assert_eq!(got.as_deref(), expected, "{config:?}");
+        }
+    }
+}
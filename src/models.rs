@@ -1,15 +1,54 @@
 use hashbrown::HashMap;
 use parking_lot::RwLock;
-use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 
-use crate::WizError;
+use crate::bulblibrary::BulbClass;
+use crate::messages::{Method, ModelConfig};
+use crate::{Result, WizError};
+use std::fmt;
 use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::sync::broadcast;
 
-#[derive(Clone, Debug)]
+/// Events buffered for slow [BulbRegistry::subscribe] receivers.
+const EVENT_CAPACITY: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct DiscoveredBulb {
     pub ip_address: String,
     pub mac_address: String,
+    /// Local interface the bulb was found on.
+    #[serde(default)]
+    pub interface: Option<String>,
+    /// Module name from getSystemConfig, when it has been queried.
+    #[serde(default)]
+    pub module_name: Option<String>,
+    #[serde(default)]
+    pub fw_version: Option<String>,
+    #[serde(default)]
+    pub home_id: Option<i64>,
+    #[serde(default)]
+    pub room_id: Option<i64>,
+    #[serde(default)]
+    pub group_id: Option<i64>,
+    #[serde(default)]
+    pub type_id: Option<i64>,
+    #[serde(default)]
+    pub class: Option<BulbClass>,
+    #[serde(default)]
+    pub model_config: Option<ModelConfig>,
+    /// `[min, max]` kelvin, empty when unknown.
+    #[serde(default)]
+    pub white_range: Vec<f64>,
+    /// `[min, max]` extended kelvin, empty when unknown.
+    #[serde(default)]
+    pub ext_white_range: Vec<f64>,
+    /// When the bulb last answered.
+    #[serde(with = "time::serde::rfc3339")]
+    pub last_seen: OffsetDateTime,
 }
 
 impl DiscoveredBulb {
@@ -17,42 +56,233 @@ impl DiscoveredBulb {
         Self {
             ip_address: ip,
             mac_address: mac,
+            interface: None,
+            module_name: None,
+            fw_version: None,
+            home_id: None,
+            room_id: None,
+            group_id: None,
+            type_id: None,
+            class: None,
+            model_config: None,
+            white_range: Vec::new(),
+            ext_white_range: Vec::new(),
+            last_seen: OffsetDateTime::now_utc(),
         }
     }
 }
 
+/// One inventory line, e.g. `ESP01_SHRGB1C_31, RGB, fw 1.25.0, 2200–6500K`.
+///
+/// Details that were not queried are left out.
+impl fmt::Display for DiscoveredBulb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = vec![self
+            .module_name
+            .clone()
+            .unwrap_or_else(|| self.mac_address.clone())];
+        if let Some(class) = &self.class {
+            parts.push(class.kind().to_string());
+        }
+        if let Some(fw) = &self.fw_version {
+            parts.push(format!("fw {fw}"));
+        }
+        if let Some(range) = self.class.as_ref().and_then(|c| c.features().kelvin_range) {
+            parts.push(format!("{:.0}–{:.0}K", range.min(), range.max()));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// Change in the set of bulbs answering discovery.
+#[derive(Clone, Debug)]
+pub enum DiscoveryEvent {
+    Appeared(DiscoveredBulb),
+    /// The bulb now answers from `bulb.ip_address` instead of `old_ip`.
+    IpChanged {
+        bulb: DiscoveredBulb,
+        old_ip: String,
+    },
+    /// The bulb has not answered for longer than the grace period.
+    Disappeared(DiscoveredBulb),
+}
+
+/// Change of a [BulbRegistry] entry.
+#[derive(Clone, Debug)]
+pub enum RegistryEvent {
+    Added(Arc<DiscoveredBulb>),
+    Updated {
+        old: Arc<DiscoveredBulb>,
+        new: Arc<DiscoveredBulb>,
+    },
+    Removed(Arc<DiscoveredBulb>),
+}
+
+/// Known bulbs keyed by MAC.
+///
+/// Entries are handed out as [Arc] snapshots: updating a bulb replaces its
+/// entry instead of mutating the one readers hold.
 pub struct BulbRegistry {
-    bulbs_by_mac: RwLock<HashMap<String, DiscoveredBulb>>,
+    bulbs_by_mac: RwLock<HashMap<String, Arc<DiscoveredBulb>>>,
+    events: broadcast::Sender<RegistryEvent>,
 }
 
 impl Default for BulbRegistry {
     fn default() -> Self {
         Self {
             bulbs_by_mac: RwLock::new(HashMap::new()),
+            events: broadcast::channel(EVENT_CAPACITY).0,
         }
     }
 }
 
 impl BulbRegistry {
-    pub fn register(&self, bulb: DiscoveredBulb) {
-        let mut w = self.bulbs_by_mac.write();
-        w.insert(bulb.mac_address.clone(), bulb);
+    /// Insert or replace `bulb`, returning the entry it replaced.
+    pub fn register(&self, bulb: DiscoveredBulb) -> Option<Arc<DiscoveredBulb>> {
+        let new = Arc::new(bulb);
+        let old = self
+            .bulbs_by_mac
+            .write()
+            .insert(new.mac_address.clone(), new.clone());
+        self.notify(new, old.clone());
+        old
+    }
+    /// Record that `bulb` answered and report how the registry changed.
+    ///
+    /// Details `bulb` lacks are kept from the current entry.
+    pub fn seen(&self, mut bulb: DiscoveredBulb) -> Option<DiscoveryEvent> {
+        let (new, old) = {
+            let mut bulbs = self.bulbs_by_mac.write();
+            let old = bulbs.get(&bulb.mac_address).cloned();
+            if let Some(old) = &old {
+                bulb.module_name = bulb.module_name.or_else(|| old.module_name.clone());
+                bulb.fw_version = bulb.fw_version.or_else(|| old.fw_version.clone());
+                bulb.home_id = bulb.home_id.or(old.home_id);
+                bulb.room_id = bulb.room_id.or(old.room_id);
+                bulb.group_id = bulb.group_id.or(old.group_id);
+                bulb.type_id = bulb.type_id.or(old.type_id);
+                bulb.class = bulb.class.or_else(|| old.class.clone());
+                bulb.model_config = bulb.model_config.or_else(|| old.model_config.clone());
+                if bulb.white_range.is_empty() {
+                    bulb.white_range = old.white_range.clone();
+                }
+                if bulb.ext_white_range.is_empty() {
+                    bulb.ext_white_range = old.ext_white_range.clone();
+                }
+            }
+            let new = Arc::new(bulb);
+            bulbs.insert(new.mac_address.clone(), new.clone());
+            (new, old)
+        };
+        self.notify(new.clone(), old.clone());
+        match old {
+            None => Some(DiscoveryEvent::Appeared((*new).clone())),
+            Some(old) if old.ip_address != new.ip_address => Some(DiscoveryEvent::IpChanged {
+                bulb: (*new).clone(),
+                old_ip: old.ip_address.clone(),
+            }),
+            Some(_) => None,
+        }
+    }
+    fn notify(&self, new: Arc<DiscoveredBulb>, old: Option<Arc<DiscoveredBulb>>) {
+        let event = match old {
+            None => RegistryEvent::Added(new),
+            Some(old) => RegistryEvent::Updated { old, new },
+        };
+        let _ = self.events.send(event);
+    }
+    pub fn remove(&self, mac: &str) -> Option<Arc<DiscoveredBulb>> {
+        let old = self.bulbs_by_mac.write().remove(mac)?;
+        let _ = self.events.send(RegistryEvent::Removed(old.clone()));
+        Some(old)
+    }
+    /// Remove and return the bulbs that have not answered for longer than `grace`.
+    pub fn prune(&self, grace: Duration) -> Vec<Arc<DiscoveredBulb>> {
+        let now = OffsetDateTime::now_utc();
+        let mut stale = Vec::new();
+        self.bulbs_by_mac.write().retain(|_, b| {
+            let keep = now - b.last_seen <= grace;
+            if !keep {
+                stale.push(b.clone());
+            }
+            keep
+        });
+        for bulb in &stale {
+            let _ = self.events.send(RegistryEvent::Removed(bulb.clone()));
+        }
+        stale
+    }
+    /// Receive a [RegistryEvent] for every later change.
+    pub fn subscribe(&self) -> broadcast::Receiver<RegistryEvent> {
+        self.events.subscribe()
     }
-    pub fn bulbs(&self) -> Vec<&DiscoveredBulb> {
-        let r = self.bulbs_by_mac.read();
-        r.par_values().collect::<Vec<&DiscoveredBulb>>()
+    pub fn bulbs(&self) -> Vec<Arc<DiscoveredBulb>> {
+        self.bulbs_by_mac.read().values().cloned().collect()
     }
-    pub fn inner(&self) -> &HashMap<String, DiscoveredBulb> {
-        &self.bulbs_by_mac.read()
+    pub fn snapshot(&self) -> HashMap<String, Arc<DiscoveredBulb>> {
+        self.bulbs_by_mac.read().clone()
     }
-    pub fn into_inner(self) -> HashMap<String, DiscoveredBulb> {
+    /// Write every bulb to `path` as JSON.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let bulbs = self.bulbs();
+        let bulbs: Vec<&DiscoveredBulb> = bulbs.iter().map(Arc::as_ref).collect();
+        std::fs::write(path, serde_json::to_vec_pretty(&bulbs)?)?;
+        Ok(())
+    }
+    /// Read a registry written by [BulbRegistry::save].
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let bulbs: Vec<DiscoveredBulb> = serde_json::from_slice(&std::fs::read(path)?)?;
+        let reg = Self::default();
+        reg.bulbs_by_mac.write().extend(
+            bulbs
+                .into_iter()
+                .map(|b| (b.mac_address.clone(), Arc::new(b))),
+        );
+        Ok(reg)
+    }
+    pub fn into_inner(self) -> HashMap<String, Arc<DiscoveredBulb>> {
         self.bulbs_by_mac.into_inner()
     }
+    pub fn len(&self) -> usize {
+        self.bulbs_by_mac.read().len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.bulbs_by_mac.read().is_empty()
+    }
     pub fn is_registered(&self, mac: &str) -> bool {
         self.bulbs_by_mac.read().contains_key(mac)
     }
-    pub fn get(&self, mac: &str) -> Option<&DiscoveredBulb> {
-        self.bulbs_by_mac.read().get(mac)
+    pub fn get(&self, mac: &str) -> Option<Arc<DiscoveredBulb>> {
+        self.bulbs_by_mac.read().get(mac).cloned()
+    }
+    pub fn get_by_ip(&self, ip: &str) -> Option<Arc<DiscoveredBulb>> {
+        self.find(|b| b.ip_address == ip)
+    }
+    /// Find the bulb with module name `name`, ignoring case.
+    ///
+    /// Bulbs of the same model share a module name, so more than one match is
+    /// reported as [WizError::AmbiguousTarget].
+    pub fn get_by_name(&self, name: &str) -> Result<Option<Arc<DiscoveredBulb>>> {
+        let mut found: Vec<Arc<DiscoveredBulb>> = self
+            .bulbs_by_mac
+            .read()
+            .values()
+            .filter(|b| {
+                b.module_name
+                    .as_deref()
+                    .is_some_and(|n| n.eq_ignore_ascii_case(name))
+            })
+            .cloned()
+            .collect();
+        if found.len() > 1 {
+            let mut macs: Vec<String> = found.iter().map(|b| b.mac_address.clone()).collect();
+            macs.sort();
+            return Err(WizError::AmbiguousTarget(name.to_string(), macs));
+        }
+        Ok(found.pop())
+    }
+    fn find(&self, pred: impl Fn(&DiscoveredBulb) -> bool) -> Option<Arc<DiscoveredBulb>> {
+        self.bulbs_by_mac.read().values().find(|b| pred(b)).cloned()
     }
 }
 
@@ -64,27 +294,104 @@ pub struct BulbRegistration {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RegistrationMessage {
-    pub method: String,
+    pub method: Method,
     pub env: String,
     pub result: BulbRegistration,
     #[serde(skip)]
     pub ip: Option<SocketAddr>,
 }
 
-impl TryInto<DiscoveredBulb> for RegistrationMessage {
+impl TryFrom<RegistrationMessage> for DiscoveredBulb {
     type Error = WizError;
-    fn try_into(self) -> Result<DiscoveredBulb, Self::Error> {
-        if !self.result.success {
-            return Err(WizError::RegErr(self));
+    fn try_from(msg: RegistrationMessage) -> std::result::Result<Self, Self::Error> {
+        if !msg.result.success {
+            return Err(WizError::RegErr(msg));
         }
-        let ip = self
-            .ip
-            .ok_or(WizError::NoIP(self.clone()))?
-            .ip()
-            .to_string();
-        Ok(DiscoveredBulb {
-            ip_address: ip,
-            mac_address: self.result.mac,
-        })
+        let ip = msg.ip.ok_or(WizError::NoIP(msg.clone()))?.ip().to_string();
+        Ok(DiscoveredBulb::new(ip, msg.result.mac))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bulb(ip: &str, mac: &str, module: &str) -> DiscoveredBulb {
+        let mut bulb = DiscoveredBulb::new(ip.to_string(), mac.to_string());
+        bulb.module_name = Some(module.to_string());
+        bulb
+    }
+
+    #[test]
+    fn get_by_name_reports_ambiguity() {
+        let reg = BulbRegistry::default();
+        reg.register(bulb("10.0.0.2", "bb", "ESP01_SHRGB1C_31"));
+        reg.register(bulb("10.0.0.3", "cc", "ESP01_SHTW1C_31"));
+        let found = reg.get_by_name("esp01_shtw1c_31").unwrap().unwrap();
+        assert_eq!(found.mac_address, "cc");
+        assert!(reg.get_by_name("ESP06_SHDW9_01").unwrap().is_none());
+        reg.register(bulb("10.0.0.1", "aa", "ESP01_SHRGB1C_31"));
+        match reg.get_by_name("ESP01_SHRGB1C_31") {
+            Err(WizError::AmbiguousTarget(name, macs)) => {
+                assert_eq!(name, "ESP01_SHRGB1C_31");
+                assert_eq!(macs, ["aa", "bb"]);
+            }
+            other => panic!("expected an ambiguous target, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn seen_keeps_details_and_prune_notifies() {
+        let reg = BulbRegistry::default();
+        let mut events = reg.subscribe();
+        assert!(matches!(
+            reg.seen(bulb("10.0.0.1", "aa", "ESP01_SHRGB1C_31")),
+            Some(DiscoveryEvent::Appeared(_))
+        ));
+        let moved = DiscoveredBulb::new("10.0.0.9".to_string(), "aa".to_string());
+        match reg.seen(moved) {
+            Some(DiscoveryEvent::IpChanged { bulb, old_ip }) => {
+                assert_eq!(old_ip, "10.0.0.1");
+                assert_eq!(bulb.module_name.as_deref(), Some("ESP01_SHRGB1C_31"));
+            }
+            other => panic!("expected an IP change, got {other:?}"),
+        }
+        let mut stale = bulb("10.0.0.2", "bb", "ESP01_SHTW1C_31");
+        stale.last_seen -= Duration::from_secs(600);
+        reg.register(stale);
+        let pruned = reg.prune(Duration::from_secs(120));
+        assert_eq!(pruned.len(), 1);
+        assert_eq!(pruned[0].mac_address, "bb");
+        assert!(reg.is_registered("aa") && !reg.is_registered("bb"));
+        assert!(matches!(events.try_recv(), Ok(RegistryEvent::Added(_))));
+        assert!(matches!(
+            events.try_recv(),
+            Ok(RegistryEvent::Updated { .. })
+        ));
+        assert!(matches!(events.try_recv(), Ok(RegistryEvent::Added(_))));
+        assert!(
+            matches!(events.try_recv(), Ok(RegistryEvent::Removed(b)) if b.mac_address == "bb")
+        );
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let mut saved = bulb("10.0.0.5", "a8bb50000001", "ESP01_SHRGB1C_31");
+        saved.class = Some(crate::testing::class("ESP01_SHRGB1C_31"));
+        saved.model_config = Some(
+            serde_json::from_value(serde_json::json!({"cctRange": [2200, 2700, 6500, 6500]}))
+                .unwrap(),
+        );
+        saved.white_range = vec![2700.0, 6500.0];
+        saved.ext_white_range = vec![2200.0, 6500.0];
+        saved.last_seen = time::macros::datetime!(2024-03-01 12:30:45.5 UTC);
+        let reg = BulbRegistry::default();
+        reg.register(saved.clone());
+        let path = std::env::temp_dir().join(format!("wiz-registry-{}.json", std::process::id()));
+        reg.save(&path).unwrap();
+        let loaded = BulbRegistry::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap().get("a8bb50000001").unwrap();
+        assert_eq!(*loaded, saved);
     }
 }
@@ -0,0 +1,542 @@
+use crate::bulblibrary::{BulbClass, DEFAULT_FAN_SPEEDS};
+use crate::scenes::SCENES;
+use crate::utils::{hex_to_percent, percent_to_hex};
+use crate::{Result, WizError};
+
+use buildstructor::buildstructor;
+use serde::{Deserialize, Serialize};
+
+/// Fan operating mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FanMode {
+    Normal,
+    /// Speed varies to mimic a breeze.
+    Breeze,
+}
+
+impl FanMode {
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(FanMode::Normal),
+            2 => Some(FanMode::Breeze),
+            _ => None,
+        }
+    }
+    pub fn code(self) -> u8 {
+        match self {
+            FanMode::Normal => 1,
+            FanMode::Breeze => 2,
+        }
+    }
+}
+
+/// State of a fan, see [PilotState::fan].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FanState {
+    pub on: bool,
+    pub mode: Option<FanMode>,
+    pub speed: Option<u8>,
+    pub reverse: bool,
+}
+
+/// Bulb state as reported by `getPilot` and pushed with `syncPilot`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PilotState {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub mac: Option<String>,
+    #[serde(default)]
+    pub state: bool,
+    /// Brightness in percent, 10-100.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub dimming: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub r: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub g: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub b: Option<u8>,
+    /// Cold white channel.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub c: Option<u8>,
+    /// Warm white channel.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub w: Option<u8>,
+    /// Color temperature in kelvin.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub temp: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub scene_id: Option<u32>,
+    /// Effect speed, 10-200.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub speed: Option<u8>,
+    /// Up/down light ratio of dual-head fixtures, 0-100.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub ratio: Option<u8>,
+    /// 1 when the fan is spinning.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub fan_state: Option<u8>,
+    /// 1 normal, 2 breeze.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub fan_mode: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub fan_speed: Option<u8>,
+    /// 1 when the fan spins in reverse.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub fan_revrs: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub rssi: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub src: Option<String>,
+}
+
+impl PilotState {
+    pub fn is_on(&self) -> bool {
+        self.state
+    }
+    pub fn rgb(&self) -> Option<(u8, u8, u8)> {
+        Some((self.r?, self.g?, self.b?))
+    }
+    pub fn rgbcw(&self) -> Option<(u8, u8, u8, u8, u8)> {
+        Some((self.r?, self.g?, self.b?, self.c?, self.w?))
+    }
+    pub fn cold_white(&self) -> Option<u8> {
+        self.c
+    }
+    pub fn warm_white(&self) -> Option<u8> {
+        self.w
+    }
+    pub fn color_temp(&self) -> Option<u32> {
+        self.temp
+    }
+    /// Name of the running scene, if any.
+    pub fn scene(&self) -> Option<&'static str> {
+        self.scene_id
+            .and_then(|id| SCENES.get(&id))
+            .map(String::as_str)
+    }
+    /// Brightness on the 0-255 scale.
+    pub fn brightness(&self) -> Option<u8> {
+        self.dimming.map(|d| percent_to_hex(d as f64) as u8)
+    }
+    pub fn brightness_percent(&self) -> Option<f64> {
+        self.brightness().map(|b| hex_to_percent(b as f64))
+    }
+    pub fn speed(&self) -> Option<u8> {
+        self.speed
+    }
+    pub fn ratio(&self) -> Option<u8> {
+        self.ratio
+    }
+    /// Fan state, `None` for devices without a fan.
+    pub fn fan(&self) -> Option<FanState> {
+        Some(FanState {
+            on: self.fan_state? == 1,
+            mode: self.fan_mode.and_then(FanMode::from_code),
+            speed: self.fan_speed,
+            reverse: self.fan_revrs == Some(1),
+        })
+    }
+}
+
+/// Parameters of a `setPilot` command.
+///
+/// Built with [Pilot::builder] and checked against the bulb with
+/// [Pilot::validate] before anything is sent.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Pilot {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dimming: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    r: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    g: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    b: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    c: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    w: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temp: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scene_id: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    speed: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ratio: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fan_state: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fan_mode: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fan_speed: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fan_revrs: Option<u8>,
+}
+
+#[buildstructor]
+impl Pilot {
+    /// `brightness` is on the 0-255 scale and is sent as 10-100 percent.
+    #[builder]
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        state: Option<bool>,
+        brightness: Option<u8>,
+        rgb: Option<(u8, u8, u8)>,
+        rgbcw: Option<(u8, u8, u8, u8, u8)>,
+        color_temp: Option<u32>,
+        scene: Option<u32>,
+        speed: Option<u8>,
+        ratio: Option<u8>,
+        fan_on: Option<bool>,
+        fan_mode: Option<FanMode>,
+        fan_speed: Option<u8>,
+        fan_reverse: Option<bool>,
+    ) -> Self {
+        let dimming = brightness.map(|b| hex_to_percent(b as f64).clamp(10.0, 100.0) as u8);
+        let (r, g, b, c, w) = match (rgbcw, rgb) {
+            (Some((r, g, b, c, w)), _) => (Some(r), Some(g), Some(b), Some(c), Some(w)),
+            (None, Some((r, g, b))) => (Some(r), Some(g), Some(b), None, None),
+            (None, None) => (None, None, None, None, None),
+        };
+        Self {
+            state,
+            dimming,
+            r,
+            g,
+            b,
+            c,
+            w,
+            temp: color_temp,
+            scene_id: scene,
+            speed,
+            ratio,
+            fan_state: fan_on.map(u8::from),
+            fan_mode: fan_mode.map(FanMode::code),
+            fan_speed,
+            fan_revrs: fan_reverse.map(u8::from),
+        }
+    }
+    pub fn on() -> Self {
+        Self {
+            state: Some(true),
+            ..Default::default()
+        }
+    }
+    pub fn off() -> Self {
+        Self {
+            state: Some(false),
+            ..Default::default()
+        }
+    }
+    /// Check that the bulb described by `class` can apply this pilot.
+    pub fn validate(&self, class: &BulbClass) -> Result<()> {
+        let feat = class.features();
+        let modes = [
+            ("RGB color", self.r.is_some()),
+            ("color temperature", self.temp.is_some()),
+            ("scene", self.scene_id.is_some()),
+        ];
+        let mut set = modes.iter().filter(|(_, s)| *s).map(|(n, _)| *n);
+        if let (Some(a), Some(b)) = (set.next(), set.next()) {
+            return Err(WizError::ConflictingModes(a, b));
+        }
+        if self.dimming.is_some() && !feat.brightness {
+            return Err(WizError::Unsupported("brightness", feat.name.clone()));
+        }
+        if self.r.is_some() && !feat.color {
+            return Err(WizError::Unsupported("RGB color", feat.name.clone()));
+        }
+        if let Some(temp) = self.temp {
+            if !feat.color_tmp {
+                return Err(WizError::Unsupported(
+                    "color temperature",
+                    feat.name.clone(),
+                ));
+            }
+            if let Some(range) = &feat.kelvin_range {
+                if !range.contains(temp as f64) {
+                    return Err(WizError::KelvinOutOfRange(temp, range.min(), range.max()));
+                }
+            }
+        }
+        if let Some(id) = self.scene_id {
+            let scene = SCENES.get(&id).ok_or(WizError::UnknownScene(id))?;
+            if !class.supports_scene(id) {
+                return Err(WizError::SceneUnsupported(scene.clone(), feat.name.clone()));
+            }
+        }
+        if let Some(speed) = self.speed {
+            if !feat.effect {
+                return Err(WizError::Unsupported("effect speed", feat.name.clone()));
+            }
+            if !(10..=200).contains(&speed) {
+                return Err(WizError::OutOfRange("speed", 10, 200, speed as i64));
+            }
+        }
+        if let Some(ratio) = self.ratio {
+            if !feat.dual_head {
+                return Err(WizError::Unsupported("dual-head ratio", feat.name.clone()));
+            }
+            if ratio > 100 {
+                return Err(WizError::OutOfRange("ratio", 0, 100, ratio as i64));
+            }
+        }
+        let fan = [
+            self.fan_state,
+            self.fan_mode,
+            self.fan_speed,
+            self.fan_revrs,
+        ];
+        if fan.iter().any(Option::is_some) && !feat.fan {
+            return Err(WizError::Unsupported("fan", feat.name.clone()));
+        }
+        if self.fan_revrs == Some(1) && !feat.fan_reverse {
+            return Err(WizError::Unsupported("fan reverse", feat.name.clone()));
+        }
+        if self.fan_mode == Some(FanMode::Breeze.code()) && !feat.fan_breeze_mode {
+            return Err(WizError::Unsupported("fan breeze mode", feat.name.clone()));
+        }
+        if let Some(speed) = self.fan_speed {
+            let max = feat.fan_speed_range.unwrap_or(DEFAULT_FAN_SPEEDS);
+            if !(1..=max).contains(&speed) {
+                return Err(WizError::OutOfRange(
+                    "fan speed",
+                    1,
+                    max as i64,
+                    speed as i64,
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bulblibrary::Features;
+    use crate::testing::class;
+    use serde_json::json;
+
+    #[test]
+    fn validate_against_class() {
+        let cases = [
+            (
+                "ESP01_SHRGB1C_31",
+                Pilot::builder().rgb((255, 0, 0)).build(),
+                None,
+            ),
+            (
+                "ESP01_SHTW1C_31",
+                Pilot::builder().rgb((255, 0, 0)).build(),
+                Some("RGB color is not supported by ESP01_SHTW1C_31"),
+            ),
+            (
+                "ESP06_SHDW9_01",
+                Pilot::builder().rgb((255, 0, 0)).build(),
+                Some("RGB color is not supported by ESP06_SHDW9_01"),
+            ),
+            (
+                "ESP06_SHDW9_01",
+                Pilot::builder().color_temp(4000u32).build(),
+                Some("color temperature is not supported by ESP06_SHDW9_01"),
+            ),
+            (
+                "ESP01_SHTW1C_31",
+                Pilot::builder().color_temp(2200u32).build(),
+                None,
+            ),
+            (
+                "ESP01_SHTW1C_31",
+                Pilot::builder().color_temp(6500u32).build(),
+                None,
+            ),
+            (
+                "ESP01_SHTW1C_31",
+                Pilot::builder().color_temp(2100u32).build(),
+                Some("Color temperature 2100K is outside of 2200K-6500K"),
+            ),
+            (
+                "ESP01_SHRGB1C_31",
+                Pilot::builder().color_temp(7000u32).build(),
+                Some("Color temperature 7000K is outside of 2200K-6500K"),
+            ),
+            (
+                "ESP01_SHRGB1C_31",
+                Pilot::builder().scene(1u32).build(),
+                None,
+            ),
+            (
+                "ESP10_SOCKET_06",
+                Pilot::builder().scene(11u32).build(),
+                Some("Scene Warm White is not supported by ESP10_SOCKET_06"),
+            ),
+            (
+                "ESP01_SHTW1C_31",
+                Pilot::builder().scene(1u32).build(),
+                Some("Scene Ocean is not supported by ESP01_SHTW1C_31"),
+            ),
+            (
+                "ESP01_SHRGB1C_31",
+                Pilot::builder().scene(999u32).build(),
+                Some("Unknown scene id 999"),
+            ),
+            (
+                "ESP01_SHRGB1C_31",
+                Pilot::builder()
+                    .rgb((255, 0, 0))
+                    .color_temp(4000u32)
+                    .build(),
+                Some("RGB color and color temperature cannot be set together"),
+            ),
+            (
+                "ESP01_SHRGB1C_31",
+                Pilot::builder().color_temp(4000u32).scene(1u32).build(),
+                Some("color temperature and scene cannot be set together"),
+            ),
+            (
+                "ESP01_SHRGB1C_31",
+                Pilot::builder().scene(1u32).speed(10u8).build(),
+                None,
+            ),
+            (
+                "ESP01_SHRGB1C_31",
+                Pilot::builder().scene(1u32).speed(200u8).build(),
+                None,
+            ),
+            (
+                "ESP01_SHRGB1C_31",
+                Pilot::builder().speed(9u8).build(),
+                Some("speed must be between 10 and 200, got 9"),
+            ),
+            (
+                "ESP01_SHRGB1C_31",
+                Pilot::builder().speed(201u8).build(),
+                Some("speed must be between 10 and 200, got 201"),
+            ),
+            (
+                "ESP01_SHRGB1C_31",
+                Pilot::builder().ratio(50u8).build(),
+                Some("dual-head ratio is not supported by ESP01_SHRGB1C_31"),
+            ),
+            (
+                "ESP01_DHRGB1C_31",
+                Pilot::builder().ratio(0u8).build(),
+                None,
+            ),
+            (
+                "ESP01_DHRGB1C_31",
+                Pilot::builder().ratio(100u8).build(),
+                None,
+            ),
+            (
+                "ESP01_DHRGB1C_31",
+                Pilot::builder().ratio(101u8).build(),
+                Some("ratio must be between 0 and 100, got 101"),
+            ),
+            (
+                "ESP10_SOCKET_06",
+                Pilot::builder().brightness(128u8).build(),
+                Some("brightness is not supported by ESP10_SOCKET_06"),
+            ),
+            ("ESP10_SOCKET_06", Pilot::on(), None),
+            (
+                "ESP01_SHRGB1C_31",
+                Pilot::builder().fan_on(true).build(),
+                Some("fan is not supported by ESP01_SHRGB1C_31"),
+            ),
+            (
+                "ESP20_FANDIMS_31",
+                Pilot::builder()
+                    .fan_on(true)
+                    .fan_mode(FanMode::Breeze)
+                    .fan_reverse(true)
+                    .fan_speed(6u8)
+                    .build(),
+                None,
+            ),
+            (
+                "ESP20_FANDIMS_31",
+                Pilot::builder().fan_speed(0u8).build(),
+                Some("fan speed must be between 1 and 6, got 0"),
+            ),
+            (
+                "ESP20_FANDIMS_31",
+                Pilot::builder().fan_speed(7u8).build(),
+                Some("fan speed must be between 1 and 6, got 7"),
+            ),
+        ];
+        for (module, pilot, expected) in cases {
+            let got = pilot.validate(&class(module)).err().map(|e| e.to_string());
+            assert_eq!(got.as_deref(), expected, "{module}: {pilot:?}");
+        }
+    }
+
+    #[test]
+    fn validate_against_basic_fan() {
+        let fan = BulbClass::Fan(
+            Features::fan_builder()
+                .name("ESP20_FANDIMS_31")
+                .dual_head(false)
+                .fan_reverse(false)
+                .fan_breeze_mode(false)
+                .build(),
+        );
+        let cases = [
+            (
+                Pilot::builder()
+                    .fan_on(true)
+                    .fan_mode(FanMode::Normal)
+                    .fan_reverse(false)
+                    .build(),
+                None,
+            ),
+            (
+                Pilot::builder().fan_reverse(true).build(),
+                Some("fan reverse is not supported by ESP20_FANDIMS_31"),
+            ),
+            (
+                Pilot::builder().fan_mode(FanMode::Breeze).build(),
+                Some("fan breeze mode is not supported by ESP20_FANDIMS_31"),
+            ),
+        ];
+        for (pilot, expected) in cases {
+            let got = pilot.validate(&fan).err().map(|e| e.to_string());
+            assert_eq!(got.as_deref(), expected, "{pilot:?}");
+        }
+    }
+
+    #[test]
+    fn brightness_is_sent_as_percent() {
+        let cases = [
+            (0, 10),
+            (13, 10),
+            (26, 10),
+            (128, 50),
+            (191, 75),
+            (255, 100),
+        ];
+        for (brightness, dimming) in cases {
+            let pilot = Pilot::builder().brightness(brightness as u8).build();
+            assert_eq!(
+                serde_json::to_value(&pilot).unwrap(),
+                json!({ "dimming": dimming }),
+                "brightness {brightness}"
+            );
+        }
+        let pilot = Pilot::builder()
+            .state(true)
+            .brightness(255u8)
+            .rgbcw((1, 2, 3, 4, 5))
+            .build();
+        assert_eq!(
+            serde_json::to_value(&pilot).unwrap(),
+            json!({"state": true, "dimming": 100, "r": 1, "g": 2, "b": 3, "c": 4, "w": 5})
+        );
+    }
+}
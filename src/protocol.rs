@@ -0,0 +1,276 @@
+use crate::discovery::PORT;
+use crate::messages::{Method, Request, Response};
+use crate::utils::create_udp_socket;
+use crate::{Result, WizError};
+
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{self as tktime, Instant};
+use tracing::{debug, instrument, trace, warn};
+
+/// Overall time a single request may take before giving up.
+pub const TIMEOUT: f64 = 13.0;
+/// Delay before the first resend of an unanswered request.
+pub const FIRST_SEND_INTERVAL: f64 = 0.5;
+/// Upper bound for the delay between two resends.
+pub const MAX_BACKOFF: f64 = 3.0;
+/// Maximum number of datagrams sent for a single request.
+pub const MAX_SEND_DATAGRAMS: u32 = 6;
+
+type PendingKey = (IpAddr, Method);
+
+struct Waiter {
+    id: u32,
+    tx: oneshot::Sender<Response>,
+}
+
+type Pending = Arc<Mutex<HashMap<PendingKey, Vec<Waiter>>>>;
+
+/// Unicast request/response transport for talking to bulbs.
+///
+/// Sends a JSON message to a bulb on [PORT], or the port set with
+/// [WizProtocol::with_port], and resends it on a backoff
+/// schedule until a reply arrives or [TIMEOUT] elapses.
+///
+/// A single socket is shared by every request: one receive task hands each
+/// reply to the request waiting on the same bulb and method whose id the
+/// bulb echoed back, or to the oldest one if the reply carries no id. Share it
+/// between bulbs with an [Arc].
+pub struct WizProtocol {
+    transport: Arc<UdpSocket>,
+    pending: Pending,
+    next_id: AtomicU32,
+    timeout: Duration,
+    port: u16,
+    receiver: JoinHandle<()>,
+}
+
+impl Drop for WizProtocol {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+/// Removes a waiter from the pending map when its request finishes or is dropped.
+struct PendingGuard<'a> {
+    pending: &'a Pending,
+    key: PendingKey,
+    id: u32,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        let mut pending = self.pending.lock();
+        if let Some(waiters) = pending.get_mut(&self.key) {
+            waiters.retain(|w| w.id != self.id);
+            if waiters.is_empty() {
+                pending.remove(&self.key);
+            }
+        }
+    }
+}
+
+impl WizProtocol {
+    /// Bind an ephemeral port and start the receive task.
+    ///
+    /// Must be called from within a tokio runtime.
+    #[instrument]
+    pub fn new() -> Result<Self> {
+        let transport = Arc::new(create_udp_socket(0)?);
+        debug!("Created the udp socket");
+        let pending = Pending::default();
+        let receiver = tokio::spawn(receive_loop(transport.clone(), pending.clone()));
+        Ok(Self {
+            transport,
+            pending,
+            next_id: AtomicU32::new(1),
+            timeout: Duration::from_secs_f64(TIMEOUT),
+            port: PORT,
+            receiver,
+        })
+    }
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    /// Talk to bulbs on `port` instead of [PORT], e.g. to a fake bulb in tests.
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+    /// Send `params` as `method` to the bulb at `ip` and return the typed result.
+    ///
+    /// Errors reported by the bulb surface as [WizError::BulbErr].
+    pub async fn call<P: Serialize, R: DeserializeOwned>(
+        &self,
+        ip: IpAddr,
+        method: Method,
+        params: P,
+    ) -> Result<R> {
+        self.request(ip, &Request::new(method, params))
+            .await?
+            .typed()?
+            .into_result()
+    }
+    /// Send `req` to the bulb at `ip` and wait for its reply.
+    ///
+    /// Requests without an id are given one, so bulbs that echo it back can be
+    /// matched exactly.
+    #[instrument(skip(self, req), fields(method = %req.method))]
+    pub async fn request<P: Serialize>(&self, ip: IpAddr, req: &Request<P>) -> Result<Response> {
+        let addr = SocketAddr::new(ip, self.port);
+        let id = req
+            .id
+            .unwrap_or_else(|| self.next_id.fetch_add(1, Ordering::Relaxed));
+        let mut msg = serde_json::to_value(req)?;
+        msg["id"] = id.into();
+        let data = serde_json::to_vec(&msg)?;
+        let key = (ip, req.method.clone());
+        let (tx, mut rx) = oneshot::channel();
+        self.pending
+            .lock()
+            .entry(key.clone())
+            .or_default()
+            .push(Waiter { id, tx });
+        let _guard = PendingGuard {
+            pending: &self.pending,
+            key,
+            id,
+        };
+        let deadline = Instant::now() + self.timeout;
+        let mut interval = Duration::from_secs_f64(FIRST_SEND_INTERVAL);
+        let mut attempts = 0;
+        loop {
+            if attempts < MAX_SEND_DATAGRAMS {
+                self.transport.send_to(&data, addr).await?;
+                attempts += 1;
+                trace!("Sent attempt {} to {}", attempts, addr);
+            }
+            let wait_until = (Instant::now() + interval).min(deadline);
+            match tktime::timeout_at(wait_until, &mut rx).await {
+                Ok(Ok(resp)) => return Ok(resp),
+                Ok(Err(_)) => return Err(WizError::NoResponse(addr, attempts)),
+                Err(_) if Instant::now() >= deadline => {
+                    return Err(WizError::NoResponse(addr, attempts));
+                }
+                Err(_) => {
+                    interval = (interval * 2).min(Duration::from_secs_f64(MAX_BACKOFF));
+                }
+            }
+        }
+    }
+}
+
+#[instrument(skip_all)]
+async fn receive_loop(transport: Arc<UdpSocket>, pending: Pending) {
+    let mut buf = [0; 4096];
+    loop {
+        let (n, from) = match transport.recv_from(&mut buf).await {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("Receive failed: {e}");
+                continue;
+            }
+        };
+        let resp: Response = match serde_json::from_slice(&buf[..n]) {
+            Ok(r) => r,
+            Err(e) => {
+                debug!("Ignoring malformed datagram from {}: {e}", from);
+                continue;
+            }
+        };
+        let key = (from.ip(), resp.method.clone());
+        let mut pending = pending.lock();
+        let Some(waiters) = pending.get_mut(&key) else {
+            debug!("No request waiting for {} from {}", resp.method, from);
+            continue;
+        };
+        // A reply echoing an id nobody waits for is a late duplicate; only
+        // bulbs that don't echo ids are matched by arrival order.
+        let pos = match resp.id {
+            Some(id) => match waiters.iter().position(|w| w.id == id) {
+                Some(pos) => pos,
+                None => {
+                    debug!("Dropping stale {} reply {} from {}", resp.method, id, from);
+                    continue;
+                }
+            },
+            None => 0,
+        };
+        let waiter = waiters.remove(pos);
+        if waiters.is_empty() {
+            pending.remove(&key);
+        }
+        trace!(
+            "Dispatching {} from {} to request {}",
+            resp.method,
+            from,
+            waiter.id
+        );
+        let _ = waiter.tx.send(resp);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    #[tokio::test]
+    async fn routes_out_of_order_replies_by_id() {
+        let bulb = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = bulb.local_addr().unwrap();
+        let proto = WizProtocol::new().unwrap().with_port(addr.port());
+        let ip = addr.ip();
+        let fake = async {
+            let mut buf = [0; 1024];
+            let mut ids = Vec::new();
+            let mut from = None;
+            while ids.len() < 2 {
+                let (n, addr) = bulb.recv_from(&mut buf).await.unwrap();
+                let req: Value = serde_json::from_slice(&buf[..n]).unwrap();
+                let id = req["id"].as_u64().unwrap();
+                if !ids.contains(&id) {
+                    ids.push(id);
+                }
+                from = Some(addr);
+            }
+            let from = from.unwrap();
+            let reply = |id: u64| {
+                serde_json::to_vec(&json!({"method": "getPilot", "id": id, "result": {"id": id}}))
+                    .unwrap()
+            };
+            // Second request first, then a duplicate nobody waits for, then the first.
+            bulb.send_to(&reply(ids[1]), from).await.unwrap();
+            bulb.send_to(&reply(9999), from).await.unwrap();
+            bulb.send_to(&reply(ids[0]), from).await.unwrap();
+            ids
+        };
+        let (req1, req2) = (
+            Request::empty(Method::GetPilot).with_id(1),
+            Request::empty(Method::GetPilot).with_id(2),
+        );
+        let first = proto.request(ip, &req1);
+        let second = proto.request(ip, &req2);
+        let (ids, first, second) = tokio::join!(fake, first, second);
+        assert_eq!(ids.len(), 2);
+        let first = first.unwrap();
+        let second = second.unwrap();
+        assert_eq!(first.id, Some(1));
+        assert_eq!(first.result, Some(json!({"id": 1})));
+        assert_eq!(second.id, Some(2));
+        assert_eq!(second.result, Some(json!({"id": 2})));
+    }
+}
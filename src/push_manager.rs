@@ -1,19 +1,76 @@
-use crate::models::DiscoveredBulb;
+use crate::messages::{Method, RegistrationParams};
+use crate::models::RegistrationMessage;
+use crate::pilot::PilotState;
+use crate::utils::create_udp_socket;
 use crate::Result;
 use crate::WizError;
-use hashbrown::HashMap;
+use buildstructor::buildstructor;
+use hashbrown::{HashMap, HashSet};
 use itertools::Itertools;
 use once_cell::sync::Lazy;
+use parking_lot::{Mutex, RwLock};
 use rand::prelude::SliceRandom;
 use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use time::OffsetDateTime;
 use tokio::net::UdpSocket;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
+use tracing::{debug, instrument, trace, warn};
 
-const RESPOND_PORT: i32 = 38899;
-const LISTEN_PORT: i32 = 38900;
+pub const RESPOND_PORT: u16 = 38899;
+pub const LISTEN_PORT: u16 = 38900;
+/// Pushes buffered for slow subscribers.
+const EVENT_CAPACITY: usize = 256;
+/// Seconds between two registrations of the same bulb.
+pub const DEFAULT_REREGISTER_INTERVAL: f64 = 60.0;
+
+/// How [PushManager] listens for pushes and registers bulbs.
+#[derive(Debug, Clone)]
+pub struct PushConfig {
+    /// Local port pushes arrive on, ephemeral when 0.
+    ///
+    /// Bulbs push to [LISTEN_PORT] whatever port registered them, so other
+    /// ports only suit fake bulbs in tests.
+    pub listen_port: u16,
+    /// Port bulbs take registrations on.
+    pub respond_port: u16,
+    /// Delay between two registrations of the same bulb.
+    pub reregister_interval: Duration,
+}
+
+impl Default for PushConfig {
+    fn default() -> Self {
+        Self {
+            listen_port: LISTEN_PORT,
+            respond_port: RESPOND_PORT,
+            reregister_interval: Duration::from_secs_f64(DEFAULT_REREGISTER_INTERVAL),
+        }
+    }
+}
+
+#[buildstructor]
+impl PushConfig {
+    #[builder]
+    pub fn new(
+        listen_port: Option<u16>,
+        respond_port: Option<u16>,
+        reregister_interval: Option<Duration>,
+    ) -> Self {
+        let default = Self::default();
+        Self {
+            listen_port: listen_port.unwrap_or(default.listen_port),
+            respond_port: respond_port.unwrap_or(default.respond_port),
+            reregister_interval: reregister_interval.unwrap_or(default.reregister_interval),
+        }
+    }
+}
 
 static MAC_CHARS: Lazy<Vec<String>> = Lazy::new(|| {
     vec![
@@ -36,29 +93,10 @@ static MAC_CHARS: Lazy<Vec<String>> = Lazy::new(|| {
     ]
 });
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct RegParams {
-    #[serde(rename = "phoneIp")]
-    phone_ip: String,
-    register: bool,
-    #[serde(rename = "phoneMac")]
-    phone_mac: String,
-}
-
-impl RegParams {
-    pub fn new(phone_ip: String, register: bool, phone_mac: String) -> Self {
-        Self {
-            phone_ip,
-            register,
-            phone_mac,
-        }
-    }
-}
-
 #[derive(Debug, Deserialize, Serialize)]
 pub struct PushRegisterMessage {
-    params: RegParams,
-    method: String,
+    params: RegistrationParams,
+    method: Method,
 }
 
 impl PushRegisterMessage {
@@ -69,14 +107,14 @@ impl PushRegisterMessage {
         let sock_addr = sock.local_addr()?;
         let ip = sock_addr
             .as_socket_ipv4()
-            .ok_or(WizError::IP6(sock_addr))?
+            .ok_or_else(|| WizError::IP6(Box::new(sock_addr)))?
             .ip()
             .to_string();
         let mac = gen_mac();
-        let params = RegParams::new(ip, true, mac);
+        let params = RegistrationParams::new(ip, true, mac);
         Ok(Self {
             params,
-            method: "registration".to_string(),
+            method: Method::Registration,
         })
     }
 }
@@ -85,10 +123,538 @@ pub fn gen_mac() -> String {
     MAC_CHARS.choose_multiple(&mut OsRng, 12).join("")
 }
 
-pub struct PushManager<F: Fn(DiscoveredBulb), FS: Fn(serde_json::Value, &str)> {
-    transport: UdpSocket,
-    push_running: bool,
-    discovery_callback: Option<F>,
-    reg_message: PushRegisterMessage,
-    subs: HashMap<String, FS>,
+/// A push decoded by its method.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PushEvent {
+    /// The bulb's state changed.
+    SyncPilot(PilotState),
+    /// The bulb just booted.
+    FirstBeat { mac: String, fw: Option<String> },
+    /// Any other method, or a push that did not decode.
+    Unknown(serde_json::Value),
+}
+
+/// A push and the bulb it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Push {
+    pub ip: IpAddr,
+    /// MAC from the push params, when present.
+    pub mac: Option<String>,
+    pub event: PushEvent,
+}
+
+impl Push {
+    fn decode(msg: serde_json::Value, ip: IpAddr) -> Self {
+        let params = &msg["params"];
+        let mac = params["mac"].as_str().map(str::to_string);
+        let method = msg["method"]
+            .as_str()
+            .map(|m| m.parse().unwrap_or_else(|e| match e {}));
+        let event = match (method, &mac) {
+            (Some(Method::SyncPilot), _) => match PilotState::deserialize(params) {
+                Ok(state) => PushEvent::SyncPilot(state),
+                Err(e) => {
+                    debug!("Cannot decode syncPilot from {}: {e}", ip);
+                    PushEvent::Unknown(msg)
+                }
+            },
+            (Some(Method::FirstBeat), Some(mac)) => PushEvent::FirstBeat {
+                mac: mac.clone(),
+                fw: params["fwVersion"].as_str().map(str::to_string),
+            },
+            _ => PushEvent::Unknown(msg),
+        };
+        Self { ip, mac, event }
+    }
+}
+
+/// Pushes from every bulb or from a single MAC, see [PushManager::subscribe].
+pub struct PushSubscription {
+    rx: broadcast::Receiver<Push>,
+    mac: Option<String>,
+}
+
+impl PushSubscription {
+    /// Wait for the next matching push.
+    ///
+    /// Fails with [RecvError::Lagged] when pushes were dropped because this
+    /// receiver fell behind, and with [RecvError::Closed] once the manager is gone.
+    pub async fn recv(&mut self) -> std::result::Result<Push, RecvError> {
+        loop {
+            let push = self.rx.recv().await?;
+            match &self.mac {
+                Some(mac) if push.mac.as_ref() != Some(mac) => continue,
+                _ => return Ok(push),
+            }
+        }
+    }
+}
+
+/// Push registration state of one bulb.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PushHealth {
+    pub mac: String,
+    /// Where the bulb last answered or pushed from.
+    pub ip: IpAddr,
+    /// When the bulb last accepted a registration.
+    pub last_registered: Option<OffsetDateTime>,
+    pub last_push: Option<OffsetDateTime>,
+}
+
+impl PushHealth {
+    fn new(mac: String, ip: IpAddr) -> Self {
+        Self {
+            mac,
+            ip,
+            last_registered: None,
+            last_push: None,
+        }
+    }
+}
+
+/// Registered bulbs, keyed by the MAC they report.
+#[derive(Debug, Default)]
+struct Registered {
+    by_mac: HashMap<String, PushHealth>,
+    /// Registered IPs whose bulb has not reported its MAC yet.
+    pending: HashSet<IpAddr>,
+}
+
+impl Registered {
+    /// The entry of the bulb with `mac`, now at `ip`, if it was registered.
+    fn get_mut(&mut self, mac: &str, ip: IpAddr) -> Option<&mut PushHealth> {
+        if !self.pending.remove(&ip) && !self.by_mac.contains_key(mac) {
+            return None;
+        }
+        let health = self
+            .by_mac
+            .entry(mac.to_string())
+            .or_insert_with(|| PushHealth::new(mac.to_string(), ip));
+        health.ip = ip;
+        Some(health)
+    }
+    fn ips(&self) -> Vec<IpAddr> {
+        self.pending
+            .iter()
+            .copied()
+            .chain(self.by_mac.values().map(|h| h.ip))
+            .unique()
+            .collect()
+    }
+}
+
+/// Receives `syncPilot` and `firstBeat` pushes from registered bulbs.
+///
+/// Pushes are decoded into [PushEvent]s and published to every
+/// [PushSubscription]. Bulbs forget registrations after a while and when they
+/// reboot, so every registered bulb is registered again on an interval and
+/// right after its `firstBeat`. Bulbs are tracked by MAC, so one that comes
+/// back at a new IP keeps being registered. Bulbs push to [LISTEN_PORT] on the address
+/// they were registered with, so only one manager can run per host.
+pub struct PushManager {
+    transport: Arc<UdpSocket>,
+    push_running: AtomicBool,
+    phone_mac: String,
+    events: broadcast::Sender<Push>,
+    config: PushConfig,
+    bulbs: RwLock<Registered>,
+    receiver: Mutex<Option<JoinHandle<()>>>,
+    keep_alive: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl PushManager {
+    /// Bind [LISTEN_PORT], registering bulbs every [DEFAULT_REREGISTER_INTERVAL].
+    pub fn new() -> Result<Arc<Self>> {
+        Self::with_config(PushConfig::default())
+    }
+    /// Bind [LISTEN_PORT], registering bulbs every `reregister_interval`.
+    pub fn with_interval(reregister_interval: Duration) -> Result<Arc<Self>> {
+        Self::with_config(
+            PushConfig::builder()
+                .reregister_interval(reregister_interval)
+                .build(),
+        )
+    }
+    #[instrument]
+    pub fn with_config(config: PushConfig) -> Result<Arc<Self>> {
+        let transport = Arc::new(create_udp_socket(config.listen_port)?);
+        debug!("Created the push socket");
+        Ok(Arc::new(Self {
+            transport,
+            push_running: AtomicBool::new(false),
+            phone_mac: gen_mac(),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            config,
+            bulbs: RwLock::new(Registered::default()),
+            receiver: Mutex::new(None),
+            keep_alive: Mutex::new(None),
+        }))
+    }
+    /// Start receiving pushes and re-registering bulbs in background tasks.
+    pub fn start(self: &Arc<Self>) {
+        if self.push_running.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let this = Arc::downgrade(self);
+        let transport = self.transport.clone();
+        *self.receiver.lock() = Some(tokio::spawn(receive_loop(transport, this.clone())));
+        *self.keep_alive.lock() = Some(tokio::spawn(keep_alive_loop(
+            this,
+            self.config.reregister_interval,
+        )));
+    }
+    pub fn stop(&self) {
+        for task in [&self.receiver, &self.keep_alive] {
+            if let Some(handle) = task.lock().take() {
+                handle.abort();
+            }
+        }
+        self.push_running.store(false, Ordering::SeqCst);
+    }
+    pub fn is_running(&self) -> bool {
+        self.push_running.load(Ordering::SeqCst)
+    }
+    /// Ask the bulb at `ip` to push its state changes to this host, and keep
+    /// asking while the manager runs.
+    #[instrument(skip(self))]
+    pub async fn register(&self, ip: IpAddr) -> Result<()> {
+        {
+            let mut bulbs = self.bulbs.write();
+            if !bulbs.by_mac.values().any(|h| h.ip == ip) {
+                bulbs.pending.insert(ip);
+            }
+        }
+        self.send_registration(ip).await
+    }
+    /// Stop re-registering the bulb at `ip`.
+    ///
+    /// The bulb keeps pushing until it forgets the current registration.
+    pub fn unregister(&self, ip: IpAddr) {
+        let mut bulbs = self.bulbs.write();
+        bulbs.pending.remove(&ip);
+        bulbs.by_mac.retain(|_, h| h.ip != ip);
+    }
+    /// Registration state of every registered bulb that reported its MAC.
+    pub fn health(&self) -> Vec<PushHealth> {
+        self.bulbs.read().by_mac.values().cloned().collect()
+    }
+    pub fn health_of(&self, mac: &str) -> Option<PushHealth> {
+        self.bulbs.read().by_mac.get(mac).cloned()
+    }
+    async fn send_registration(&self, ip: IpAddr) -> Result<()> {
+        let target = SocketAddr::new(ip, self.config.respond_port);
+        let mut msg = PushRegisterMessage::new(&target.to_string())?;
+        msg.params.phone_mac = self.phone_mac.clone();
+        self.transport
+            .send_to(&serde_json::to_vec(&msg)?, target)
+            .await?;
+        Ok(())
+    }
+    pub fn config(&self) -> &PushConfig {
+        &self.config
+    }
+    /// Receive the pushes of every bulb.
+    pub fn subscribe(&self) -> PushSubscription {
+        PushSubscription {
+            rx: self.events.subscribe(),
+            mac: None,
+        }
+    }
+    /// Receive only the pushes of the bulb with `mac`.
+    pub fn subscribe_mac(&self, mac: &str) -> PushSubscription {
+        PushSubscription {
+            rx: self.events.subscribe(),
+            mac: Some(mac.to_string()),
+        }
+    }
+    fn handle(self: &Arc<Self>, msg: serde_json::Value, addr: SocketAddr) {
+        if msg["method"] == "registration" {
+            return self.registered(msg, addr);
+        }
+        let push = Push::decode(msg, addr.ip());
+        trace!("Push from {}: {:?}", addr, push.event);
+        let known = match &push.mac {
+            Some(mac) => match self.bulbs.write().get_mut(mac, push.ip) {
+                Some(health) => {
+                    health.last_push = Some(OffsetDateTime::now_utc());
+                    true
+                }
+                None => false,
+            },
+            None => false,
+        };
+        if known && matches!(push.event, PushEvent::FirstBeat { .. }) {
+            debug!("{} rebooted, registering again", push.ip);
+            let this = self.clone();
+            let ip = push.ip;
+            tokio::spawn(async move {
+                if let Err(e) = this.send_registration(ip).await {
+                    warn!("Registering {} failed: {e}", ip);
+                }
+            });
+        }
+        let _ = self.events.send(push);
+    }
+    /// Record the bulb's answer to a registration.
+    fn registered(&self, msg: serde_json::Value, addr: SocketAddr) {
+        let reply: RegistrationMessage = match serde_json::from_value(msg) {
+            Ok(r) => r,
+            Err(e) => return debug!("Cannot decode registration reply from {}: {e}", addr),
+        };
+        if !reply.result.success {
+            return warn!("{} refused the registration", addr.ip());
+        }
+        match self.bulbs.write().get_mut(&reply.result.mac, addr.ip()) {
+            Some(health) => health.last_registered = Some(OffsetDateTime::now_utc()),
+            None => debug!("Registration reply from unregistered {}", addr.ip()),
+        }
+    }
+}
+
+impl Drop for PushManager {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Register every known bulb again each `interval` until the manager is dropped.
+async fn keep_alive_loop(manager: Weak<PushManager>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let Some(manager) = manager.upgrade() else {
+            return;
+        };
+        let ips = manager.bulbs.read().ips();
+        for ip in ips {
+            if let Err(e) = manager.send_registration(ip).await {
+                warn!("Registering {} failed: {e}", ip);
+            }
+        }
+    }
+}
+
+#[instrument(skip_all)]
+async fn receive_loop(transport: Arc<UdpSocket>, manager: Weak<PushManager>) {
+    let mut buf = [0; 4096];
+    loop {
+        let (n, addr) = match transport.recv_from(&mut buf).await {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("Receive failed: {e}");
+                continue;
+            }
+        };
+        let msg: serde_json::Value = match serde_json::from_slice(&buf[..n]) {
+            Ok(m) => m,
+            Err(e) => {
+                debug!("Ignoring malformed push from {}: {e}", addr);
+                continue;
+            }
+        };
+        match manager.upgrade() {
+            Some(manager) => manager.handle(msg, addr),
+            None => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pilot::{FanMode, FanState};
+    use serde_json::json;
+
+    fn ip() -> IpAddr {
+        "192.168.1.20".parse().unwrap()
+    }
+
+    #[test]
+    fn decode_sync_pilot() {
+        let msg = json!({
+            "method": "syncPilot",
+            "env": "pro",
+            "params": {
+                "mac": "a8bb50aabbcc",
+                "rssi": -60,
+                "src": "udp",
+                "state": true,
+                "sceneId": 0,
+                "temp": 2700,
+                "dimming": 40,
+                "fanState": 1,
+                "fanMode": 2,
+                "fanSpeed": 4,
+                "fanRevrs": 1
+            }
+        });
+        let push = Push::decode(msg, ip());
+        assert_eq!(push.ip, ip());
+        assert_eq!(push.mac.as_deref(), Some("a8bb50aabbcc"));
+        let PushEvent::SyncPilot(state) = push.event else {
+            panic!("expected syncPilot, got {:?}", push.event);
+        };
+        assert!(state.is_on());
+        assert_eq!(state.color_temp(), Some(2700));
+        assert_eq!(state.dimming, Some(40));
+        assert_eq!(state.rssi, Some(-60));
+        assert_eq!(
+            state.fan(),
+            Some(FanState {
+                on: true,
+                mode: Some(FanMode::Breeze),
+                speed: Some(4),
+                reverse: true,
+            })
+        );
+    }
+
+    #[test]
+    fn decode_sync_pilot_without_fan() {
+        let msg = json!({
+            "method": "syncPilot",
+            "params": {"mac": "a8bb50aabbcc", "state": false, "r": 255, "g": 0, "b": 0}
+        });
+        let PushEvent::SyncPilot(state) = Push::decode(msg, ip()).event else {
+            panic!("expected syncPilot");
+        };
+        assert!(!state.is_on());
+        assert_eq!(state.rgb(), Some((255, 0, 0)));
+        assert_eq!(state.fan(), None);
+    }
+
+    #[test]
+    fn decode_first_beat() {
+        let msg = json!({
+            "method": "firstBeat",
+            "env": "pro",
+            "params": {"mac": "a8bb50aabbcc", "homeId": 1, "fwVersion": "1.26.1"}
+        });
+        let push = Push::decode(msg, ip());
+        assert_eq!(
+            push.event,
+            PushEvent::FirstBeat {
+                mac: "a8bb50aabbcc".to_string(),
+                fw: Some("1.26.1".to_string()),
+            }
+        );
+        let msg = json!({"method": "firstBeat", "params": {}});
+        let push = Push::decode(msg.clone(), ip());
+        assert_eq!(push.mac, None);
+        assert_eq!(push.event, PushEvent::Unknown(msg));
+    }
+
+    #[test]
+    fn decode_unknown() {
+        let cases = [
+            json!({"method": "syncAccessory", "params": {"mac": "a8bb50aabbcc"}}),
+            json!({"method": "syncPilot", "params": {"mac": "a8bb50aabbcc", "dimming": "high"}}),
+            json!({"params": {"mac": "a8bb50aabbcc"}}),
+            json!([1, 2, 3]),
+        ];
+        for msg in cases {
+            let push = Push::decode(msg.clone(), ip());
+            assert_eq!(push.event, PushEvent::Unknown(msg));
+        }
+    }
+
+    /// A manager that registers the fake bulb `bulb` and listens on an
+    /// ephemeral port, with the address the bulb sees it at.
+    async fn manager_for(bulb: &UdpSocket) -> (Arc<PushManager>, SocketAddr) {
+        let bulb_addr = bulb.local_addr().unwrap();
+        let config = PushConfig::builder()
+            .listen_port(0u16)
+            .respond_port(bulb_addr.port())
+            .build();
+        let manager = PushManager::with_config(config).unwrap();
+        manager.start();
+        manager.register(bulb_addr.ip()).await.unwrap();
+        let mut buf = [0; 1024];
+        let (n, from) = bulb.recv_from(&mut buf).await.unwrap();
+        let msg: serde_json::Value = serde_json::from_slice(&buf[..n]).unwrap();
+        assert_eq!(msg["method"], "registration");
+        (manager, from)
+    }
+
+    fn registration_reply(mac: &str, success: bool) -> String {
+        let result = json!({"mac": mac, "success": success});
+        json!({"method": "registration", "env": "pro", "result": result}).to_string()
+    }
+
+    async fn wait_for_health(manager: &PushManager, mac: &str) -> PushHealth {
+        tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                if let Some(h) = manager.health_of(mac) {
+                    return h;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn tracks_bulbs_by_mac() {
+        let mac = "a8bb50aabbcc";
+        let bulb = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (manager, from) = manager_for(&bulb).await;
+        assert!(manager.health().is_empty());
+
+        bulb.send_to(registration_reply(mac, false).as_bytes(), from)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(manager.health().is_empty());
+
+        bulb.send_to(registration_reply(mac, true).as_bytes(), from)
+            .await
+            .unwrap();
+        let health = wait_for_health(&manager, mac).await;
+        assert_eq!(health.ip, bulb.local_addr().unwrap().ip());
+        assert!(health.last_registered.is_some());
+        assert!(health.last_push.is_none());
+
+        let mut pushes = manager.subscribe_mac(mac);
+        let sync = json!({"method": "syncPilot", "params": {"mac": mac, "state": true}});
+        bulb.send_to(sync.to_string().as_bytes(), from)
+            .await
+            .unwrap();
+        let push = pushes.recv().await.unwrap();
+        assert!(matches!(push.event, PushEvent::SyncPilot(_)));
+        assert!(manager.health_of(mac).unwrap().last_push.is_some());
+    }
+
+    /// Needs a second loopback address, which only Linux routes by default.
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn follows_first_beat_to_new_ip() {
+        let mac = "a8bb50aabbcc";
+        let old = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = old.local_addr().unwrap().port();
+        let new = UdpSocket::bind(("127.0.0.2", port)).await.unwrap();
+        let (manager, from) = manager_for(&old).await;
+        old.send_to(registration_reply(mac, true).as_bytes(), from)
+            .await
+            .unwrap();
+        wait_for_health(&manager, mac).await;
+
+        let mut pushes = manager.subscribe_mac(mac);
+        let beat = json!({"method": "firstBeat", "params": {"mac": mac, "fwVersion": "1.26.1"}});
+        new.send_to(beat.to_string().as_bytes(), from)
+            .await
+            .unwrap();
+        let push = pushes.recv().await.unwrap();
+        assert!(matches!(push.event, PushEvent::FirstBeat { .. }));
+        let mut buf = [0; 1024];
+        let (n, _) = tokio::time::timeout(Duration::from_secs(1), new.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let msg: serde_json::Value = serde_json::from_slice(&buf[..n]).unwrap();
+        assert_eq!(msg["method"], "registration");
+        let health = manager.health_of(mac).unwrap();
+        assert_eq!(health.ip, new.local_addr().unwrap().ip());
+        assert!(health.last_push.is_some());
+        assert_eq!(manager.bulbs.read().ips(), [health.ip]);
+    }
 }
@@ -0,0 +1,319 @@
+use crate::bulblibrary::{Features, KelvinRange};
+
+/// Color temperature of sRGB white (D65).
+pub const SRGB_WHITE_KELVIN: f64 = 6500.0;
+/// White channels assumed when the bulb does not report `nowc`.
+pub const DEFAULT_WHITE_CHANNELS: i64 = 2;
+
+fn to_u8(v: f64) -> u8 {
+    (v * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+fn gamma_compress(v: f64) -> f64 {
+    if v <= 0.0031308 {
+        12.92 * v
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn gamma_expand(v: f64) -> f64 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// `h` is in degrees, `s` and `v` in percent.
+pub fn hsv_to_rgb(h: f64, s: f64, v: f64) -> (u8, u8, u8) {
+    let h = h.rem_euclid(360.0) / 60.0;
+    let s = (s / 100.0).clamp(0.0, 1.0);
+    let v = (v / 100.0).clamp(0.0, 1.0);
+    let c = v * s;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u8 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = v - c;
+    (to_u8(r + m), to_u8(g + m), to_u8(b + m))
+}
+
+pub fn rgb_to_hsv(rgb: (u8, u8, u8)) -> (f64, f64, f64) {
+    let (r, g, b) = (
+        rgb.0 as f64 / 255.0,
+        rgb.1 as f64 / 255.0,
+        rgb.2 as f64 / 255.0,
+    );
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+    let h = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let s = if max == 0.0 { 0.0 } else { delta / max };
+    (h, s * 100.0, max * 100.0)
+}
+
+pub fn hs_to_rgb(h: f64, s: f64) -> (u8, u8, u8) {
+    hsv_to_rgb(h, s, 100.0)
+}
+
+pub fn rgb_to_hs(rgb: (u8, u8, u8)) -> (f64, f64) {
+    let (h, s, _) = rgb_to_hsv(rgb);
+    (h, s)
+}
+
+/// Convert a CIE 1931 xy chromaticity to sRGB at full brightness.
+pub fn xy_to_rgb(x: f64, y: f64) -> (u8, u8, u8) {
+    if y <= 0.0 {
+        return (0, 0, 0);
+    }
+    let big_y = 1.0;
+    let big_x = big_y / y * x;
+    let big_z = big_y / y * (1.0 - x - y);
+    let r = big_x * 3.2406 - big_y * 1.5372 - big_z * 0.4986;
+    let g = -big_x * 0.9689 + big_y * 1.8758 + big_z * 0.0415;
+    let b = big_x * 0.0557 - big_y * 0.2040 + big_z * 1.0570;
+    let (r, g, b) = (
+        gamma_compress(r.max(0.0)),
+        gamma_compress(g.max(0.0)),
+        gamma_compress(b.max(0.0)),
+    );
+    let max = r.max(g).max(b);
+    if max <= 0.0 {
+        return (0, 0, 0);
+    }
+    (to_u8(r / max), to_u8(g / max), to_u8(b / max))
+}
+
+/// Convert sRGB to a CIE 1931 xy chromaticity. Black maps to the D65 white point.
+pub fn rgb_to_xy(rgb: (u8, u8, u8)) -> (f64, f64) {
+    let r = gamma_expand(rgb.0 as f64 / 255.0);
+    let g = gamma_expand(rgb.1 as f64 / 255.0);
+    let b = gamma_expand(rgb.2 as f64 / 255.0);
+    let big_x = r * 0.4124 + g * 0.3576 + b * 0.1805;
+    let big_y = r * 0.2126 + g * 0.7152 + b * 0.0722;
+    let big_z = r * 0.0193 + g * 0.1192 + b * 0.9505;
+    let sum = big_x + big_y + big_z;
+    if sum == 0.0 {
+        return (0.3127, 0.3290);
+    }
+    (big_x / sum, big_y / sum)
+}
+
+/// Share of cold and warm white, summing to 1, for `kelvin` within `range`.
+fn cw_mix(kelvin: f64, range: &KelvinRange) -> (f64, f64) {
+    let span = range.max() - range.min();
+    let cold = if span <= 0.0 {
+        1.0
+    } else {
+        ((kelvin - range.min()) / span).clamp(0.0, 1.0)
+    };
+    (cold, 1.0 - cold)
+}
+
+/// Cold and warm channel levels producing `kelvin` at full output.
+///
+/// Kelvin outside of `range` is clamped to its ends.
+pub fn kelvin_to_cw(kelvin: f64, range: &KelvinRange) -> (u8, u8) {
+    let (c, w) = cw_mix(kelvin, range);
+    (to_u8(c), to_u8(w))
+}
+
+/// Convert sRGB to the bulb's RGBCW channels.
+///
+/// The white common to all three colors is extracted, and
+/// [Features::white_to_color_ratio] percent of it is moved to the white LEDs.
+/// With one white channel it all goes to the warm LEDs; with two it is split
+/// to match sRGB white within [Features::kelvin_range]. Bulbs reporting no
+/// white channels get their RGB back untouched.
+pub fn rgb_to_rgbcw(rgb: (u8, u8, u8), features: &Features) -> (u8, u8, u8, u8, u8) {
+    let (r, g, b) = (rgb.0 as f64, rgb.1 as f64, rgb.2 as f64);
+    let channels = features.white_channels.unwrap_or(DEFAULT_WHITE_CHANNELS);
+    if channels <= 0 {
+        return (rgb.0, rgb.1, rgb.2, 0, 0);
+    }
+    let ratio = features.white_to_color_ratio.unwrap_or(100).clamp(0, 100) as f64 / 100.0;
+    let white = r.min(g).min(b) * ratio;
+    let (cold, warm) = match (channels, &features.kelvin_range) {
+        (1, _) => (0.0, 1.0),
+        (_, Some(range)) => cw_mix(SRGB_WHITE_KELVIN, range),
+        (_, None) => (1.0, 0.0),
+    };
+    let level = |v: f64| v.round().clamp(0.0, 255.0) as u8;
+    (
+        level(r - white),
+        level(g - white),
+        level(b - white),
+        level(white * cold),
+        level(white * warm),
+    )
+}
+
+pub fn hs_to_rgbcw(h: f64, s: f64, features: &Features) -> (u8, u8, u8, u8, u8) {
+    rgb_to_rgbcw(hs_to_rgb(h, s), features)
+}
+
+pub fn xy_to_rgbcw(x: f64, y: f64, features: &Features) -> (u8, u8, u8, u8, u8) {
+    rgb_to_rgbcw(xy_to_rgb(x, y), features)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::close;
+
+    fn features(channels: Option<i64>, wcr: Option<i64>, range: Option<(f64, f64)>) -> Features {
+        Features {
+            white_channels: channels,
+            white_to_color_ratio: wcr,
+            kelvin_range: range.map(|(min, max)| KelvinRange::new(max, min)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn hsv_primaries() {
+        let primaries = [
+            (0.0, (255, 0, 0)),
+            (60.0, (255, 255, 0)),
+            (120.0, (0, 255, 0)),
+            (180.0, (0, 255, 255)),
+            (240.0, (0, 0, 255)),
+            (300.0, (255, 0, 255)),
+            (360.0, (255, 0, 0)),
+        ];
+        for (h, rgb) in primaries {
+            assert_eq!(hsv_to_rgb(h, 100.0, 100.0), rgb, "hue {h}");
+            assert_eq!(hs_to_rgb(h, 100.0), rgb, "hue {h}");
+            let (h2, s, v) = rgb_to_hsv(rgb);
+            assert!(close(h2, h % 360.0, 1e-9), "{rgb:?} gave hue {h2}");
+            assert_eq!((s, v), (100.0, 100.0));
+        }
+        assert_eq!(hsv_to_rgb(0.0, 0.0, 100.0), (255, 255, 255));
+        assert_eq!(hsv_to_rgb(200.0, 100.0, 0.0), (0, 0, 0));
+        assert_eq!(rgb_to_hsv((0, 0, 0)), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn hsv_round_trip() {
+        let colors = [
+            (255, 128, 0),
+            (12, 200, 99),
+            (1, 2, 3),
+            (250, 250, 251),
+            (128, 0, 64),
+            (77, 77, 77),
+        ];
+        for rgb in colors {
+            let (h, s, v) = rgb_to_hsv(rgb);
+            assert_eq!(hsv_to_rgb(h, s, v), rgb);
+        }
+        let (h, s) = rgb_to_hs((255, 128, 0));
+        assert_eq!(hs_to_rgb(h, s), (255, 128, 0));
+    }
+
+    #[test]
+    fn xy_white_point() {
+        let (x, y) = rgb_to_xy((255, 255, 255));
+        assert!(
+            close(x, 0.3127, 1e-3) && close(y, 0.3290, 1e-3),
+            "({x}, {y})"
+        );
+        assert_eq!(rgb_to_xy((0, 0, 0)), (0.3127, 0.3290));
+        assert_eq!(xy_to_rgb(0.3127, 0.3290), (255, 255, 255));
+        assert_eq!(xy_to_rgb(0.3, 0.0), (0, 0, 0));
+    }
+
+    #[test]
+    fn xy_round_trip() {
+        for rgb in [(255, 0, 0), (0, 255, 0), (0, 0, 255), (255, 128, 0)] {
+            let (x, y) = rgb_to_xy(rgb);
+            let back = xy_to_rgb(x, y);
+            let diff = [
+                back.0.abs_diff(rgb.0),
+                back.1.abs_diff(rgb.1),
+                back.2.abs_diff(rgb.2),
+            ];
+            assert!(
+                diff.iter().all(|d| *d <= 2),
+                "{rgb:?} came back as {back:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn kelvin_to_cw_range() {
+        let range = KelvinRange::new(6500.0, 2700.0);
+        assert_eq!(kelvin_to_cw(2700.0, &range), (0, 255));
+        assert_eq!(kelvin_to_cw(6500.0, &range), (255, 0));
+        assert_eq!(kelvin_to_cw(4600.0, &range), (128, 128));
+        assert_eq!(kelvin_to_cw(1000.0, &range), (0, 255));
+        assert_eq!(kelvin_to_cw(9000.0, &range), (255, 0));
+        let point = KelvinRange::new(2700.0, 2700.0);
+        assert_eq!(kelvin_to_cw(2700.0, &point), (255, 0));
+    }
+
+    #[test]
+    fn rgbcw_white_channels() {
+        let rgb = (200, 150, 100);
+        let cases = [
+            (
+                features(Some(0), None, Some((2700.0, 6500.0))),
+                (200, 150, 100, 0, 0),
+            ),
+            (
+                features(Some(1), None, Some((2700.0, 6500.0))),
+                (100, 50, 0, 0, 100),
+            ),
+            (
+                features(Some(2), None, Some((2700.0, 6500.0))),
+                (100, 50, 0, 100, 0),
+            ),
+            (
+                features(None, None, Some((2700.0, 6500.0))),
+                (100, 50, 0, 100, 0),
+            ),
+            (
+                features(Some(2), None, Some((2200.0, 10800.0))),
+                (100, 50, 0, 50, 50),
+            ),
+            (features(Some(2), None, None), (100, 50, 0, 100, 0)),
+        ];
+        for (feat, expected) in cases {
+            assert_eq!(rgb_to_rgbcw(rgb, &feat), expected, "{feat:?}");
+        }
+    }
+
+    #[test]
+    fn rgbcw_white_to_color_ratio() {
+        let rgb = (200, 150, 100);
+        let cases = [
+            (Some(100), (100, 50, 0, 0, 100)),
+            (Some(50), (150, 100, 50, 0, 50)),
+            (Some(0), (200, 150, 100, 0, 0)),
+            (Some(150), (100, 50, 0, 0, 100)),
+            (None, (100, 50, 0, 0, 100)),
+        ];
+        for (wcr, expected) in cases {
+            let feat = features(Some(1), wcr, None);
+            assert_eq!(rgb_to_rgbcw(rgb, &feat), expected, "wcr {wcr:?}");
+        }
+        let feat = features(Some(2), Some(20), Some((2700.0, 6500.0)));
+        assert_eq!(rgb_to_rgbcw((255, 255, 255), &feat), (204, 204, 204, 51, 0));
+        assert_eq!(hs_to_rgbcw(0.0, 100.0, &feat), (255, 0, 0, 0, 0));
+        assert_eq!(xy_to_rgbcw(0.3127, 0.3290, &feat), (204, 204, 204, 51, 0));
+    }
+}
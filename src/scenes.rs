@@ -37,3 +37,9 @@ pub static SCENES: Lazy<HashMap<u32, String>> = lazy_sync_map! {
     32 => "Steampunk".to_string(),
     1000 => "Rhythm".to_string(),
 };
+
+/// Scenes available on Tunable White bulbs.
+pub static TW_SCENES: &[u32] = &[6, 9, 10, 11, 12, 13, 14, 15, 16, 18, 29, 30, 31, 32];
+
+/// Scenes available on Dimmable White bulbs.
+pub static DW_SCENES: &[u32] = &[9, 10, 13, 14, 29, 30, 31, 32];
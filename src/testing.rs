@@ -0,0 +1,101 @@
+//! Fixtures shared by the unit tests.
+
+use crate::bulblibrary::BulbClass;
+use crate::protocol::WizProtocol;
+
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+/// Whether `a` and `b` differ by at most `eps`.
+pub(crate) fn close(a: f64, b: f64, eps: f64) -> bool {
+    (a - b).abs() <= eps
+}
+
+/// Class of `module` with the 2200-6500K range most bulbs report.
+pub(crate) fn class(module: &str) -> BulbClass {
+    BulbClass::from_data(module, Some(vec![2200.0, 6500.0]), None, None, None, None).unwrap()
+}
+
+/// Reply member carrying `result`.
+pub(crate) fn ok(result: Value) -> Option<Value> {
+    Some(json!({ "result": result }))
+}
+
+/// Reply member of a method the bulb does not know.
+pub(crate) fn method_not_found() -> Option<Value> {
+    Some(json!({"error": {"code": -32601, "message": "Method not found"}}))
+}
+
+/// A bulb on a loopback address, answering each request with a handler.
+///
+/// The handler gets the request and returns the `result` or `error` member of
+/// the reply, see [ok] and [method_not_found], or `None` to stay silent.
+pub(crate) struct FakeBulb {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<Value>>>,
+    task: JoinHandle<()>,
+}
+
+impl FakeBulb {
+    /// Bind `addr`, e.g. `127.0.0.1:0`, and answer requests until dropped.
+    pub(crate) async fn spawn<F>(addr: impl tokio::net::ToSocketAddrs, mut handler: F) -> Self
+    where
+        F: FnMut(&Value) -> Option<Value> + Send + 'static,
+    {
+        let sock = UdpSocket::bind(addr).await.unwrap();
+        let addr = sock.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = requests.clone();
+        let task = tokio::spawn(async move {
+            let mut buf = [0; 4096];
+            loop {
+                let (n, from) = sock.recv_from(&mut buf).await.unwrap();
+                let Ok(req) = serde_json::from_slice::<Value>(&buf[..n]) else {
+                    continue;
+                };
+                log.lock().push(req.clone());
+                if let Some(mut reply) = handler(&req) {
+                    reply["method"] = req["method"].clone();
+                    reply["id"] = req["id"].clone();
+                    reply["env"] = "pro".into();
+                    let _ = sock
+                        .send_to(&serde_json::to_vec(&reply).unwrap(), from)
+                        .await;
+                }
+            }
+        });
+        Self {
+            addr,
+            requests,
+            task,
+        }
+    }
+    pub(crate) fn ip(&self) -> IpAddr {
+        self.addr.ip()
+    }
+    pub(crate) fn port(&self) -> u16 {
+        self.addr.port()
+    }
+    /// A transport that talks to bulbs on this bulb's port.
+    pub(crate) fn transport(&self) -> WizProtocol {
+        WizProtocol::new().unwrap().with_port(self.port())
+    }
+    /// Number of `method` datagrams received, resends included.
+    pub(crate) fn received(&self, method: &str) -> usize {
+        self.requests
+            .lock()
+            .iter()
+            .filter(|r| r["method"] == method)
+            .count()
+    }
+}
+
+impl Drop for FakeBulb {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
@@ -1,10 +1,11 @@
 use crate::{Result, WizError};
 
 use pnet::datalink::interfaces;
+use pnet::ipnetwork::{IpNetwork, Ipv4Network};
 
 use rayon::prelude::*;
 use socket2::{Domain, Protocol, Socket, Type};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket as StdSocket};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket as StdSocket};
 use time::format_description;
 use tokio::net::UdpSocket;
 
@@ -15,6 +16,14 @@ pub(crate) fn get_timestamp() -> Result<String> {
         .map_err(WizError::from)
 }
 
+/// Lowercase hex digits of `mac`, without separators such as `:` or `-`.
+pub(crate) fn normalize_mac(mac: &str) -> String {
+    mac.chars()
+        .filter(|c| c.is_ascii_hexdigit())
+        .collect::<String>()
+        .to_lowercase()
+}
+
 pub(crate) fn hex_to_percent(hex_val: f64) -> f64 {
     ((hex_val / 255.0) * 100.0).round()
 }
@@ -47,7 +56,62 @@ pub fn get_local_adddrs() -> Vec<String> {
         .collect()
 }
 
+/// IPv4 network of a local interface.
+#[derive(Debug, Clone)]
+pub struct InterfaceNet {
+    pub name: String,
+    pub network: Ipv4Network,
+}
+
+impl InterfaceNet {
+    /// Directed broadcast address of the subnet.
+    pub fn broadcast(&self) -> Ipv4Addr {
+        self.network.broadcast()
+    }
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        self.network.contains(ip)
+    }
+}
+
+/// IPv4 networks of every interface that is up and can broadcast.
+pub fn get_broadcast_nets() -> Vec<InterfaceNet> {
+    interfaces()
+        .into_iter()
+        .filter(|x| x.is_up() && x.is_broadcast() && !x.is_loopback())
+        .flat_map(|x| {
+            x.ips.into_iter().filter_map(move |ip| match ip {
+                IpNetwork::V4(network) => Some(InterfaceNet {
+                    name: x.name.clone(),
+                    network,
+                }),
+                IpNetwork::V6(_) => None,
+            })
+        })
+        .collect()
+}
+
+/// IPv4 address of the network interface called `name`.
+pub fn get_interface_addr(name: &str) -> Option<Ipv4Addr> {
+    interfaces()
+        .into_iter()
+        .find(|x| x.name == name)?
+        .ips
+        .into_iter()
+        .find_map(|x| match x.ip() {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        })
+}
+
 pub fn create_udp(listen_port: u16, reuseaddr: bool, broadcast: bool) -> Result<UdpSocket> {
+    create_udp_bound(
+        SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, listen_port),
+        reuseaddr,
+        broadcast,
+    )
+}
+
+pub fn create_udp_bound(addr: SocketAddrV4, reuseaddr: bool, broadcast: bool) -> Result<UdpSocket> {
     let sock = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
     if reuseaddr {
         sock.set_reuse_address(true)?;
@@ -55,8 +119,7 @@ pub fn create_udp(listen_port: u16, reuseaddr: bool, broadcast: bool) -> Result<
     if broadcast {
         sock.set_broadcast(true)?;
     }
-    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), listen_port);
-    let addr = addr.into();
+    let addr = SocketAddr::V4(addr).into();
     sock.bind(&addr)?;
     let res: StdSocket = sock.into();
     res.set_nonblocking(true)?;